@@ -12,4 +12,8 @@ fn main() {
     tracing::debug!(field = "value", "my message");
     let _span2 = tracing::trace_span!("span.trace").entered();
     tracing::error!(field = "only one");
+    let span3 = tracing::info_span!("span.record", late = tracing::field::Empty);
+    span3.record("late", "recorded after creation");
+    let _span3 = span3.entered();
+    tracing::info!("late field is shown");
 }
@@ -0,0 +1,589 @@
+//! A builder for configuring a [`Layer`].
+//!
+//! See the documentation on [`Builder`] for more details.
+use std::{any::TypeId, env, io, time::Duration};
+
+use is_terminal::IsTerminal;
+use tracing::level_filters::LevelFilter;
+use tracing_subscriber::fmt::MakeWriter;
+
+use crate::{
+    fmt::{Format, Palette, ScopeStyle, SpanEvents, Timestamp},
+    Clock, Directives, Focus, KindFilter, Layer, RecorderLimit, Trigger,
+};
+
+/// A builder for configuring and creating a [`Layer`].
+///
+/// A builder is created with [`Layer::builder`]. All configuration options have defaults which
+/// match the output of [`Layer::new`], so only the options which should differ need to be set.
+///
+/// # Examples
+///
+/// ```rust
+/// use tracing_subscriber::prelude::*;
+///
+/// use ari_subscriber::{Layer, ScopeStyle, SpanEvents, Timestamp};
+///
+/// let layer = Layer::builder()
+///     .with_writer(std::io::stderr)
+///     .with_timestamp(Timestamp::Time)
+///     .with_span_events(SpanEvents::NEW | SpanEvents::CLOSE)
+///     .with_scope_style(ScopeStyle::Compact)
+///     .build();
+/// tracing_subscriber::registry().with(layer).init();
+///
+/// // Will be printed to `stderr` by `ari_subscriber`
+/// tracing::info!("configured!");
+/// ```
+pub struct Builder<W = fn() -> io::Stdout> {
+    pub(crate) make_writer: W,
+    pub(crate) ansi: Option<bool>,
+    writer_is_terminal: fn() -> bool,
+    pub(crate) format: Format,
+    pub(crate) timestamp: Timestamp,
+    pub(crate) span_events: SpanEvents,
+    pub(crate) scope_style: ScopeStyle,
+    pub(crate) palette: Palette,
+    pub(crate) max_level: LevelFilter,
+    pub(crate) kind_filter: KindFilter,
+    pub(crate) directives: Directives,
+    pub(crate) lost_waker_detection: bool,
+    pub(crate) busy_poll_threshold: Option<Duration>,
+    pub(crate) sched_latency_threshold: Option<Duration>,
+    pub(crate) summary: bool,
+    pub(crate) leak_detection: bool,
+    pub(crate) flight_recorder: Option<RecorderLimit>,
+    pub(crate) trigger: Option<Trigger>,
+    pub(crate) focus: Option<Focus>,
+    clock: Option<Clock>,
+    pub(crate) deterministic: bool,
+}
+
+impl Default for Builder {
+    fn default() -> Self {
+        Self {
+            make_writer: io::stdout,
+            ansi: None,
+            writer_is_terminal: || io::stdout().is_terminal(),
+            format: Format::default(),
+            timestamp: Timestamp::default(),
+            span_events: SpanEvents::ALL,
+            scope_style: ScopeStyle::default(),
+            palette: Palette::default(),
+            max_level: LevelFilter::TRACE,
+            kind_filter: KindFilter::default(),
+            directives: Directives::default(),
+            lost_waker_detection: false,
+            busy_poll_threshold: None,
+            sched_latency_threshold: None,
+            summary: false,
+            leak_detection: false,
+            flight_recorder: None,
+            trigger: None,
+            focus: None,
+            clock: None,
+            deterministic: false,
+        }
+    }
+}
+
+impl<W> Builder<W>
+where
+    W: for<'w> MakeWriter<'w> + 'static,
+{
+    /// Sets the [`MakeWriter`] that the [`Layer`] will use to write its output.
+    ///
+    /// By default, output is written to `stdout`. Any type implementing [`MakeWriter`] can be
+    /// used, such as [`std::io::stderr`], a [`std::fs::File`] or a `Mutex<Vec<u8>>`.
+    ///
+    /// Each line of output is written with a single call to the writer, so lines written from
+    /// different threads will not be interleaved.
+    ///
+    /// If the writer is [`std::io::stdout`] or [`std::io::stderr`], ANSI escape codes are used
+    /// when it is a terminal. Any other writer is assumed not to be a terminal, so ANSI escape
+    /// codes will not be used unless they are enabled with [`Builder::with_ansi`].
+    ///
+    /// # Examples
+    ///
+    /// ```rust
+    /// use std::sync::Mutex;
+    ///
+    /// use ari_subscriber::Layer;
+    ///
+    /// let layer = Layer::builder()
+    ///     .with_writer(Mutex::new(Vec::<u8>::new()))
+    ///     .build();
+    /// ```
+    #[must_use]
+    pub fn with_writer<W2>(self, make_writer: W2) -> Builder<W2>
+    where
+        W2: for<'w> MakeWriter<'w> + 'static,
+    {
+        Builder {
+            make_writer,
+            ansi: self.ansi,
+            writer_is_terminal: writer_is_terminal::<W2>(),
+            format: self.format,
+            timestamp: self.timestamp,
+            span_events: self.span_events,
+            scope_style: self.scope_style,
+            palette: self.palette,
+            max_level: self.max_level,
+            kind_filter: self.kind_filter,
+            directives: self.directives,
+            lost_waker_detection: self.lost_waker_detection,
+            busy_poll_threshold: self.busy_poll_threshold,
+            sched_latency_threshold: self.sched_latency_threshold,
+            summary: self.summary,
+            leak_detection: self.leak_detection,
+            flight_recorder: self.flight_recorder,
+            trigger: self.trigger,
+            focus: self.focus,
+            clock: self.clock,
+            deterministic: self.deterministic,
+        }
+    }
+
+    /// Sets whether ANSI escape codes are used to colorize the output.
+    ///
+    /// When disabled, the output has the same layout, but no colors or other styling.
+    ///
+    /// By default, ANSI escape codes are used only when writing to `stdout` or `stderr`, the
+    /// writer is a terminal, and the `NO_COLOR` environment variable is not set. Calling this
+    /// method overrides that detection in either direction.
+    ///
+    /// When ANSI escape codes are used, the detection in the [`colored`] crate, which is global
+    /// to the process, is overridden so that it writes them.
+    ///
+    /// [`colored`]: https://docs.rs/colored
+    ///
+    /// # Examples
+    ///
+    /// ```rust
+    /// use ari_subscriber::Layer;
+    ///
+    /// // Plain output, suitable for writing to a file or CI logs.
+    /// let layer = Layer::builder().with_ansi(false).build();
+    /// ```
+    #[must_use]
+    pub fn with_ansi(mut self, ansi: bool) -> Self {
+        self.ansi = Some(ansi);
+        self
+    }
+
+    /// Sets the format in which each line is written.
+    ///
+    /// Defaults to [`Format::Text`]. The other options below only apply to [`Format::Text`].
+    ///
+    /// # Examples
+    ///
+    /// ```rust
+    /// use ari_subscriber::{Format, Layer};
+    ///
+    /// // Write JSON Lines to be processed by other tools.
+    /// let layer = Layer::builder().with_format(Format::Json).build();
+    /// ```
+    #[must_use]
+    pub fn with_format(mut self, format: Format) -> Self {
+        self.format = format;
+        self
+    }
+
+    /// Sets how the timestamp at the beginning of each line is displayed.
+    ///
+    /// Defaults to [`Timestamp::DateTime`].
+    #[must_use]
+    pub fn with_timestamp(mut self, timestamp: Timestamp) -> Self {
+        self.timestamp = timestamp;
+        self
+    }
+
+    /// Sets which span lifecycle messages (`new`, `enter`, `exit`, `close`) are printed.
+    ///
+    /// Defaults to [`SpanEvents::ALL`].
+    #[must_use]
+    pub fn with_span_events(mut self, span_events: SpanEvents) -> Self {
+        self.span_events = span_events;
+        self
+    }
+
+    /// Sets how the spans in the scope of each line are displayed.
+    ///
+    /// Defaults to [`ScopeStyle::Full`].
+    #[must_use]
+    pub fn with_scope_style(mut self, scope_style: ScopeStyle) -> Self {
+        self.scope_style = scope_style;
+        self
+    }
+
+    /// Sets how spans and events are colored, when ANSI escape codes are used.
+    ///
+    /// With [`Palette::Task`], each task has its own color, which makes it easier to tell apart
+    /// the output of tasks which run interleaved. Defaults to [`Palette::Kind`].
+    ///
+    /// # Examples
+    ///
+    /// ```rust
+    /// use ari_subscriber::{Layer, Palette};
+    ///
+    /// let layer = Layer::builder()
+    ///     .with_ansi(true)
+    ///     .with_palette(Palette::Task)
+    ///     .build();
+    /// ```
+    #[must_use]
+    pub fn with_palette(mut self, palette: Palette) -> Self {
+        self.palette = palette;
+        self
+    }
+
+    /// Sets the most verbose level of spans and events which will be output.
+    ///
+    /// Spans and events more verbose than this level are ignored by ari, but are still recorded
+    /// for any other layers in the same registry. Defaults to [`LevelFilter::TRACE`], as Tokio's
+    /// instrumentation is all emitted at `TRACE` level.
+    #[must_use]
+    pub fn with_max_level(mut self, max_level: impl Into<LevelFilter>) -> Self {
+        self.max_level = max_level.into();
+        self
+    }
+
+    /// Sets which kinds of spans and events are output.
+    ///
+    /// Spans and events which aren't selected are ignored by ari, in the same way as those above
+    /// the maximum level. See [`KindFilter`] for an example. Defaults to [`KindFilter::all`].
+    ///
+    /// To also disable the callsites which aren't selected, add the filter to the built layer
+    /// with [`Layer::with_filter`] instead.
+    ///
+    /// [`Layer::with_filter`]: tracing_subscriber::Layer::with_filter
+    #[must_use]
+    pub fn with_kind_filter(mut self, kind_filter: KindFilter) -> Self {
+        self.kind_filter = kind_filter;
+        self
+    }
+
+    /// Sets filtering directives, such as those read from the `ARI_LOG` environment variable.
+    ///
+    /// See [`Directives`] for the syntax. If the directives list any kinds of spans and events,
+    /// they replace the filter set with [`Builder::with_kind_filter`]. By default, there are no
+    /// directives.
+    ///
+    /// # Examples
+    ///
+    /// ```rust
+    /// use ari_subscriber::{Directives, Layer};
+    ///
+    /// let layer = Layer::builder()
+    ///     .with_directives(Directives::parse("tasks,wakers,my_app=debug").unwrap())
+    ///     .build();
+    /// ```
+    #[must_use]
+    pub fn with_directives(mut self, directives: Directives) -> Self {
+        if let Some(kind_filter) = directives.kind_filter() {
+            self.kind_filter = kind_filter.clone();
+        }
+        self.directives = directives;
+        self
+    }
+
+    /// Sets whether a warning is printed when a task may have lost its waker.
+    ///
+    /// A task which finishes a poll with no live wakers, and which wasn't woken during that
+    /// poll, will never be polled again. Waker clones, drops, and wakes are counted from Tokio's
+    /// waker events to detect this.
+    ///
+    /// As the poll which completes a task also leaves it without wakers, the warning is printed
+    /// when the next span or event is recorded on the same thread, unless that is the task being
+    /// dropped. The `block_on` task, whose waker isn't instrumented, is never reported.
+    ///
+    /// Defaults to `false`.
+    #[must_use]
+    pub fn with_lost_waker_detection(mut self, enabled: bool) -> Self {
+        self.lost_waker_detection = enabled;
+        self
+    }
+
+    /// Sets a threshold above which a single poll of a task is reported as a long poll.
+    ///
+    /// A task which is busy for a long time in a single poll blocks the worker thread it is
+    /// running on, often because it calls blocking code from within async code. When a poll takes
+    /// longer than `threshold`, a warning is printed with the task's spawn location, the duration
+    /// of the poll, and the resources entered during the poll. By default, there is no threshold.
+    ///
+    /// # Examples
+    ///
+    /// ```rust
+    /// use std::time::Duration;
+    ///
+    /// use ari_subscriber::Layer;
+    ///
+    /// let layer = Layer::builder()
+    ///     .with_busy_poll_threshold(Duration::from_micros(500))
+    ///     .build();
+    /// ```
+    #[must_use]
+    pub fn with_busy_poll_threshold(mut self, threshold: Duration) -> Self {
+        self.busy_poll_threshold = Some(threshold);
+        self
+    }
+
+    /// Sets a threshold above which the scheduling latency of a task is reported.
+    ///
+    /// The scheduling latency is the time between a task being woken and the runtime starting
+    /// to poll it, which is displayed on the `enter` line of the task's span, e.g.
+    /// `enter (sched 45µs)`. A high scheduling latency means that the runtime's workers are too
+    /// busy to poll tasks as soon as they are ready. When the latency is longer than `threshold`,
+    /// a warning is printed with the task's spawn location. By default, there is no threshold.
+    ///
+    /// # Examples
+    ///
+    /// ```rust
+    /// use std::time::Duration;
+    ///
+    /// use ari_subscriber::Layer;
+    ///
+    /// let layer = Layer::builder()
+    ///     .with_sched_latency_threshold(Duration::from_millis(1))
+    ///     .build();
+    /// ```
+    #[must_use]
+    pub fn with_sched_latency_threshold(mut self, threshold: Duration) -> Self {
+        self.sched_latency_threshold = Some(threshold);
+        self
+    }
+
+    /// Sets whether a summary report is written at the end of the run.
+    ///
+    /// The report is written when the [`Layer`] is dropped, or when the guard returned by
+    /// [`Layer::summary_guard`] is dropped, whichever happens first. It covers the tasks spawned,
+    /// completed, and still alive, the spawn locations with the most polls and busy time, the
+    /// resources which were never dropped, and the number of events of each kind. See
+    /// [`SummaryGuard`] for an example. Defaults to `false`.
+    ///
+    /// The summary is only written with [`Format::Text`], as it would make the output of the
+    /// other formats invalid.
+    ///
+    /// [`SummaryGuard`]: crate::SummaryGuard
+    #[must_use]
+    pub fn with_summary(mut self, enabled: bool) -> Self {
+        self.summary = enabled;
+        self
+    }
+
+    /// Sets whether the task, resource, and async op spans which are never closed are listed.
+    ///
+    /// Each span which was created but never closed is listed with its id, the location it was
+    /// created at, its age, and its last lifecycle message. The list is written at the same time
+    /// as the summary report (see [`Builder::with_summary`]), or on demand with
+    /// [`Handle::write_leaks`]. Defaults to `false`.
+    ///
+    /// As with the summary, the list is only written with [`Format::Text`].
+    ///
+    /// [`Handle::write_leaks`]: crate::Handle::write_leaks
+    #[must_use]
+    pub fn with_leak_detection(mut self, enabled: bool) -> Self {
+        self.leak_detection = enabled;
+        self
+    }
+
+    /// Keeps the most recent lines in memory instead of writing them, up to `limit`.
+    ///
+    /// Writing every line of Tokio's instrumentation is slow and produces a lot of output. In
+    /// flight recorder mode, lines are still formatted, but are kept in a ring buffer which only
+    /// holds the most recent lines. The buffer is written out (and emptied) when:
+    /// - a `WARN` or `ERROR` line is recorded, including the warnings generated by ari itself,
+    /// - [`Handle::dump`] is called,
+    /// - the process panics, if the panic hook has been installed with
+    ///   [`Handle::dump_on_panic`].
+    ///
+    /// This gives the lead-up to a failure without the cost of writing everything. The summary
+    /// report and leak list are written directly. By default, every line is written directly.
+    ///
+    /// With [`Format::ChromeTrace`], the lines which open the trace and name each task's track
+    /// may have been evicted by the time the buffer is written out. If a trigger is set with
+    /// [`Builder::with_trigger`], it takes the place of the flight recorder.
+    ///
+    /// [`Handle::dump`]: crate::Handle::dump
+    /// [`Handle::dump_on_panic`]: crate::Handle::dump_on_panic
+    ///
+    /// # Examples
+    ///
+    /// ```rust
+    /// use tracing_subscriber::prelude::*;
+    ///
+    /// use ari_subscriber::{Layer, RecorderLimit};
+    ///
+    /// let layer = Layer::builder()
+    ///     .with_flight_recorder(RecorderLimit::Lines(10_000))
+    ///     .build();
+    /// let handle = layer.handle();
+    /// handle.dump_on_panic();
+    /// tracing_subscriber::registry().with(layer).init();
+    ///
+    /// // Only kept in memory.
+    /// tracing::info!("all is well");
+    /// // Writes out the line above, followed by this one.
+    /// tracing::warn!("something went wrong");
+    /// ```
+    #[must_use]
+    pub fn with_flight_recorder(mut self, limit: RecorderLimit) -> Self {
+        self.flight_recorder = Some(limit);
+        self
+    }
+
+    /// Only writes output around the time that a [`Trigger`] fires.
+    ///
+    /// Until the trigger fires, the most recent lines are kept in a pre-trigger buffer and
+    /// nothing is written. When it fires, the buffer is written, followed by the lines in the
+    /// capture window. See [`Trigger`] for the available conditions and options. The summary
+    /// report and leak list are written directly. By default, there is no trigger and every line
+    /// is written.
+    #[must_use]
+    pub fn with_trigger(mut self, trigger: Trigger) -> Self {
+        self.trigger = Some(trigger);
+        self
+    }
+
+    /// Only writes the output related to the task selected by a [`Focus`].
+    ///
+    /// A line is written if the task is in its scope, or if it is a waker event targeting the
+    /// task. With [`Focus::with_dimming`], the other lines are written in grey instead of being
+    /// left out. The focus can be changed or cleared at runtime through a [`Handle`]. By
+    /// default, there is no focus and the output of every task is written.
+    ///
+    /// [`Handle`]: crate::Handle
+    #[must_use]
+    pub fn with_focus(mut self, focus: Focus) -> Self {
+        self.focus = Some(focus);
+        self
+    }
+
+    /// Sets the [`Clock`] which timestamps each line.
+    ///
+    /// The clock is also used to measure poll durations, scheduling latencies, the busy and idle
+    /// times of tasks, and capture windows. Defaults to [`Clock::system`], or [`Clock::logical`] in
+    /// deterministic mode (see [`Builder::with_deterministic`]).
+    #[must_use]
+    pub fn with_clock(mut self, clock: Clock) -> Self {
+        self.clock = Some(clock);
+        self
+    }
+
+    /// Sets whether the output is deterministic, so that it can be compared against golden
+    /// files in snapshot tests.
+    ///
+    /// In deterministic mode:
+    /// - timestamps come from [`Clock::logical`], unless another clock is set with
+    ///   [`Builder::with_clock`],
+    /// - span ids are renumbered densely from 1, in the order the spans are created,
+    /// - absolute paths in `loc.file` fields are made relative to the current directory or, for
+    ///   dependencies, to the directory of the crate, e.g. `tokio-1.34.0/src/time/sleep.rs`.
+    ///
+    /// The output is only the same between runs if the spans and events are too, which
+    /// generally requires Tokio's current thread runtime. Defaults to `false`.
+    ///
+    /// # Examples
+    ///
+    /// ```rust
+    /// use std::sync::{Arc, Mutex};
+    ///
+    /// use ari_subscriber::{Layer, Timestamp};
+    /// use tracing_subscriber::prelude::*;
+    ///
+    /// #[derive(Clone, Default)]
+    /// struct Output(Arc<Mutex<Vec<u8>>>);
+    ///
+    /// impl std::io::Write for Output {
+    ///     fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+    ///         self.0.lock().unwrap().write(buf)
+    ///     }
+    ///
+    ///     fn flush(&mut self) -> std::io::Result<()> {
+    ///         Ok(())
+    ///     }
+    /// }
+    ///
+    /// let output = Output::default();
+    /// let make_writer = output.clone();
+    /// let layer = Layer::builder()
+    ///     .with_writer(move || make_writer.clone())
+    ///     .with_timestamp(Timestamp::Uptime)
+    ///     .with_deterministic(true)
+    ///     .build();
+    /// let subscriber = tracing_subscriber::registry().with(layer);
+    ///
+    /// tracing::subscriber::with_default(subscriber, || {
+    ///     let span = tracing::info_span!("request");
+    ///     let _guard = span.enter();
+    ///     tracing::info!("handled");
+    /// });
+    ///
+    /// let output = String::from_utf8(output.0.lock().unwrap().clone()).unwrap();
+    /// assert!(output.starts_with("   0.000001s  INFO request[1]{} new\n"));
+    /// ```
+    #[must_use]
+    pub fn with_deterministic(mut self, enabled: bool) -> Self {
+        self.deterministic = enabled;
+        self
+    }
+
+    /// Builds the configured [`Layer`].
+    #[must_use = "A Layer does nothing if it is not added to a registry."]
+    pub fn build(self) -> Layer<W> {
+        Layer::from_builder(self)
+    }
+
+    /// Determines the clock, either as set explicitly or the default for the mode.
+    pub(crate) fn resolve_clock(&self) -> Clock {
+        match &self.clock {
+            Some(clock) => clock.clone(),
+            None if self.deterministic => Clock::logical(),
+            None => Clock::system(),
+        }
+    }
+
+    /// Determines whether ANSI escape codes should be used, either as set explicitly or
+    /// detected from the environment and the writer.
+    pub(crate) fn resolve_ansi(&self) -> bool {
+        resolve_ansi(self.ansi, self.writer_is_terminal)
+    }
+}
+
+/// Determines whether ANSI escape codes should be used, either as set explicitly with `ansi` or
+/// detected from the environment and the writer.
+pub(crate) fn resolve_ansi(ansi: Option<bool>, writer_is_terminal: fn() -> bool) -> bool {
+    let ansi = ansi.unwrap_or_else(|| !no_color() && writer_is_terminal());
+    if ansi {
+        colored::control::set_override(true);
+    }
+    ansi
+}
+
+/// Returns a check for whether the writers made by `W` write to a terminal.
+///
+/// Only `stdout` and `stderr` are checked, passed either as the functions themselves or as
+/// function pointers. Other writers are assumed not to be terminals.
+pub(crate) fn writer_is_terminal<W: 'static>() -> fn() -> bool {
+    fn type_id_of<T: 'static>(_: &T) -> TypeId {
+        TypeId::of::<T>()
+    }
+
+    let make_writer = TypeId::of::<W>();
+    if make_writer == type_id_of(&io::stdout) || make_writer == TypeId::of::<fn() -> io::Stdout>() {
+        || io::stdout().is_terminal()
+    } else if make_writer == type_id_of(&io::stderr)
+        || make_writer == TypeId::of::<fn() -> io::Stderr>()
+    {
+        || io::stderr().is_terminal()
+    } else {
+        || false
+    }
+}
+
+/// Returns `true` if the `NO_COLOR` environment variable is set to a non-empty value.
+///
+/// See <https://no-color.org/>.
+fn no_color() -> bool {
+    env::var_os("NO_COLOR").map_or(false, |value| !value.is_empty())
+}
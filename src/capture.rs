@@ -0,0 +1,282 @@
+//! Triggered capture, which only writes output around the time a trigger condition is met.
+//!
+//! See the documentation on [`Trigger`] for more details.
+use std::{io::Write, sync::Mutex, time::Duration};
+
+use chrono::{DateTime, Utc};
+use tracing_subscriber::fmt::MakeWriter;
+
+use crate::{
+    fmt::location_matches,
+    recorder::{Recorder, RecorderLimit},
+    stats::elapsed,
+    Clock,
+};
+
+/// A condition which starts writing output, like the trigger of a logic analyser.
+///
+/// Until the trigger fires, nothing is written, but the most recent lines are kept in a
+/// pre-trigger buffer. When the trigger fires, the buffered lines are written, followed by the
+/// lines in the capture window after the trigger. After the capture window, the trigger is
+/// either re-armed or nothing more is written.
+///
+/// Set with [`Builder::with_trigger`].
+///
+/// [`Builder::with_trigger`]: crate::Builder::with_trigger
+///
+/// # Examples
+///
+/// ```rust
+/// use std::time::Duration;
+///
+/// use ari_subscriber::{CaptureLength, Layer, RecorderLimit, Trigger};
+///
+/// let trigger = Trigger::long_poll(Duration::from_millis(10))
+///     .with_pre_trigger(RecorderLimit::Lines(500))
+///     .with_capture_length(CaptureLength::Duration(Duration::from_secs(1)))
+///     .with_rearm(true);
+/// let layer = Layer::builder().with_trigger(trigger).build();
+/// ```
+#[derive(Clone, Debug)]
+pub struct Trigger {
+    condition: Condition,
+    pre_trigger: RecorderLimit,
+    capture_length: CaptureLength,
+    rearm: bool,
+}
+
+#[derive(Clone, Debug)]
+enum Condition {
+    Target(String),
+    SpawnLocation(String),
+    LongPoll(Duration),
+}
+
+/// How much output is written after a [`Trigger`] fires.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CaptureLength {
+    /// Write this many lines after the trigger fires, including the line which fired it.
+    Lines(usize),
+    /// Write all lines for this long after the trigger fires, as measured by the layer's
+    /// [`Clock`].
+    Duration(Duration),
+}
+
+impl Trigger {
+    /// Fires on an event with the given target, or a target within it, e.g. `my_crate::net`
+    /// matches events with the target `my_crate::net::server`.
+    pub fn target(target: impl Into<String>) -> Self {
+        Self::new(Condition::Target(target.into()))
+    }
+
+    /// Fires when a task is spawned at the given location, either `file:line` or just `file`,
+    /// as it appears in the task span's `loc.file` and `loc.line` fields.
+    pub fn spawn_location(location: impl Into<String>) -> Self {
+        Self::new(Condition::SpawnLocation(location.into()))
+    }
+
+    /// Fires when a single poll of a task takes longer than `threshold`.
+    pub fn long_poll(threshold: Duration) -> Self {
+        Self::new(Condition::LongPoll(threshold))
+    }
+
+    fn new(condition: Condition) -> Self {
+        Self {
+            condition,
+            pre_trigger: RecorderLimit::Lines(1_000),
+            capture_length: CaptureLength::Lines(1_000),
+            rearm: false,
+        }
+    }
+
+    /// Sets how many lines from before the trigger fires are kept and written when it fires.
+    ///
+    /// Defaults to [`RecorderLimit::Lines(1000)`](RecorderLimit::Lines).
+    #[must_use]
+    pub fn with_pre_trigger(mut self, pre_trigger: RecorderLimit) -> Self {
+        self.pre_trigger = pre_trigger;
+        self
+    }
+
+    /// Sets how much output is written after the trigger fires.
+    ///
+    /// Defaults to [`CaptureLength::Lines(1000)`](CaptureLength::Lines).
+    #[must_use]
+    pub fn with_capture_length(mut self, capture_length: CaptureLength) -> Self {
+        self.capture_length = capture_length;
+        self
+    }
+
+    /// Sets whether the trigger is re-armed after the capture window, so that it can fire again.
+    ///
+    /// Defaults to `false`, in which case nothing more is written after the capture window.
+    #[must_use]
+    pub fn with_rearm(mut self, rearm: bool) -> Self {
+        self.rearm = rearm;
+        self
+    }
+
+    fn matches(&self, occurrence: &Occurrence<'_>) -> bool {
+        match (&self.condition, occurrence) {
+            (
+                Condition::Target(target),
+                Occurrence::Event {
+                    target: event_target,
+                },
+            ) => event_target
+                .strip_prefix(target.as_str())
+                .map_or(false, |rest| rest.is_empty() || rest.starts_with("::")),
+            (
+                Condition::SpawnLocation(location),
+                Occurrence::Spawn {
+                    location: spawned_at,
+                },
+            ) => spawned_at.map_or(false, |spawned_at| location_matches(spawned_at, location)),
+            (Condition::LongPoll(threshold), Occurrence::Poll { duration }) => duration > threshold,
+            _ => false,
+        }
+    }
+}
+
+/// Something which happened that may fire a [`Trigger`].
+pub(crate) enum Occurrence<'a> {
+    Event { target: &'a str },
+    Spawn { location: Option<&'a str> },
+    Poll { duration: Duration },
+}
+
+/// The state of a triggered capture.
+pub(crate) struct Capture {
+    trigger: Trigger,
+    pre_trigger: Recorder,
+    clock: Clock,
+    state: Mutex<CaptureState>,
+}
+
+enum CaptureState {
+    /// Waiting for the trigger to fire, lines are kept in the pre-trigger buffer.
+    Armed,
+    /// The trigger has fired, lines are written until the capture window ends.
+    Capturing {
+        lines: usize,
+        started: DateTime<Utc>,
+    },
+    /// The capture window has ended and the trigger wasn't re-armed, lines are discarded.
+    Finished,
+}
+
+impl Capture {
+    pub(crate) fn new(trigger: Trigger, clock: Clock) -> Self {
+        Self {
+            pre_trigger: Recorder::new(trigger.pre_trigger),
+            trigger,
+            clock,
+            state: Mutex::new(CaptureState::Armed),
+        }
+    }
+
+    /// Fires the trigger if it is armed and the occurrence matches its condition, writing the
+    /// pre-trigger buffer.
+    pub(crate) fn check<W>(&self, occurrence: &Occurrence<'_>, make_writer: &W)
+    where
+        W: for<'w> MakeWriter<'w> + 'static,
+    {
+        let mut state = self.state();
+        if !matches!(*state, CaptureState::Armed) || !self.trigger.matches(occurrence) {
+            return;
+        }
+        self.pre_trigger.dump(make_writer);
+        *state = CaptureState::Capturing {
+            lines: 0,
+            started: self.clock.now(),
+        };
+    }
+
+    /// Writes a line, including its trailing newline, if the capture window is open, otherwise
+    /// keeps it in the pre-trigger buffer or discards it.
+    pub(crate) fn write<W>(&self, line: String, make_writer: &W)
+    where
+        W: for<'w> MakeWriter<'w> + 'static,
+    {
+        let mut state = self.state();
+        if let CaptureState::Capturing { lines, started } = &mut *state {
+            let window_open = match self.trigger.capture_length {
+                CaptureLength::Lines(max_lines) => *lines < max_lines,
+                CaptureLength::Duration(duration) => elapsed(*started, self.clock.now()) < duration,
+            };
+            if window_open {
+                *lines += 1;
+                // The state stays locked while writing so that the capture window is exact.
+                let mut writer = make_writer.make_writer();
+                let _ = writer.write_all(line.as_bytes());
+                return;
+            }
+            *state = if self.trigger.rearm {
+                CaptureState::Armed
+            } else {
+                CaptureState::Finished
+            };
+        }
+
+        if let CaptureState::Armed = *state {
+            self.pre_trigger.record(line);
+        }
+    }
+
+    fn state(&self) -> std::sync::MutexGuard<'_, CaptureState> {
+        self.state
+            .lock()
+            .unwrap_or_else(std::sync::PoisonError::into_inner)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tracing_subscriber::prelude::*;
+
+    use super::{CaptureLength, Trigger};
+    use crate::{test_support::Output, Layer, RecorderLimit, Timestamp};
+
+    /// Emits eleven numbered events, of which the fourth and eighth have the trigger's target,
+    /// and returns the numbers of the events which were written.
+    fn captured(rearm: bool) -> Vec<String> {
+        let output = Output::default();
+        let trigger = Trigger::target("fire")
+            .with_pre_trigger(RecorderLimit::Lines(2))
+            .with_capture_length(CaptureLength::Lines(2))
+            .with_rearm(rearm);
+        let layer = Layer::builder()
+            .with_writer(output.clone())
+            .with_ansi(false)
+            .with_timestamp(Timestamp::None)
+            .with_trigger(trigger)
+            .build();
+        tracing::subscriber::with_default(tracing_subscriber::registry().with(layer), || {
+            for n in 1..=11 {
+                if n % 4 == 0 {
+                    tracing::info!(target: "fire", n);
+                } else {
+                    tracing::info!(n);
+                }
+            }
+        });
+        output
+            .lines()
+            .iter()
+            .map(|line| line.rsplit(' ').next().unwrap().to_owned())
+            .collect()
+    }
+
+    #[test]
+    fn writes_the_pre_trigger_buffer_and_capture_window_once() {
+        assert_eq!(captured(false), ["n=2", "n=3", "n=4", "n=5"]);
+    }
+
+    #[test]
+    fn rearmed_triggers_fire_again() {
+        assert_eq!(
+            captured(true),
+            ["n=2", "n=3", "n=4", "n=5", "n=6", "n=7", "n=8", "n=9"]
+        );
+    }
+}
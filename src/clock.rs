@@ -0,0 +1,106 @@
+//! The source of the timestamps in the output.
+//!
+//! See the documentation on [`Clock`] for more details.
+use std::{
+    fmt,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    time::{Duration, SystemTime},
+};
+
+use chrono::{DateTime, Utc};
+
+/// The source of the timestamps of each line, and of the durations derived from them.
+///
+/// Set with [`Builder::with_clock`]. Besides the timestamp at the beginning of each line, the
+/// clock is used to measure poll durations, scheduling latencies, the busy and idle times of
+/// tasks, and the length of a [`CaptureLength::Duration`] capture window.
+///
+/// [`CaptureLength::Duration`]: crate::CaptureLength::Duration
+/// [`Builder::with_clock`]: crate::Builder::with_clock
+///
+/// # Examples
+///
+/// ```rust
+/// use std::time::{Duration, SystemTime};
+///
+/// use ari_subscriber::{Clock, Layer};
+///
+/// // Every line has the same timestamp.
+/// let layer = Layer::builder()
+///     .with_clock(Clock::from_fn(|| {
+///         SystemTime::UNIX_EPOCH + Duration::from_secs(1_700_000_000)
+///     }))
+///     .build();
+/// ```
+#[derive(Clone, Default)]
+pub struct Clock {
+    kind: ClockKind,
+}
+
+#[derive(Clone, Default)]
+enum ClockKind {
+    #[default]
+    System,
+    Logical(Arc<AtomicU64>),
+    Custom(Arc<dyn Fn() -> SystemTime + Send + Sync>),
+}
+
+impl Clock {
+    /// The system clock, which is the default.
+    #[must_use]
+    pub fn system() -> Self {
+        Self {
+            kind: ClockKind::System,
+        }
+    }
+
+    /// A logical clock, which starts at the Unix epoch and advances by one microsecond each
+    /// time it is read.
+    ///
+    /// The timestamps and durations in the output then only depend on the order of the spans
+    /// and events, not on how long things took.
+    #[must_use]
+    pub fn logical() -> Self {
+        Self {
+            kind: ClockKind::Logical(Arc::new(AtomicU64::new(0))),
+        }
+    }
+
+    /// A clock which calls `now` each time it is read.
+    #[must_use]
+    pub fn from_fn<F>(now: F) -> Self
+    where
+        F: Fn() -> SystemTime + Send + Sync + 'static,
+    {
+        Self {
+            kind: ClockKind::Custom(Arc::new(now)),
+        }
+    }
+
+    pub(crate) fn now(&self) -> DateTime<Utc> {
+        match &self.kind {
+            ClockKind::System => Utc::now(),
+            ClockKind::Logical(ticks) => {
+                let micros = ticks.fetch_add(1, Ordering::Relaxed);
+                (SystemTime::UNIX_EPOCH + Duration::from_micros(micros)).into()
+            }
+            ClockKind::Custom(now) => now().into(),
+        }
+    }
+}
+
+impl fmt::Debug for Clock {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let kind = match self.kind {
+            ClockKind::System => "System",
+            ClockKind::Logical(_) => "Logical",
+            ClockKind::Custom(_) => "Custom",
+        };
+        f.debug_tuple("Clock")
+            .field(&format_args!("{kind}"))
+            .finish()
+    }
+}
@@ -0,0 +1,588 @@
+//! Configuring ari from a string of directives, such as the `ARI_LOG` environment variable.
+//!
+//! See the documentation on [`Directives`] for more details.
+use std::{env, error::Error, fmt, str::FromStr};
+
+use tracing::{level_filters::LevelFilter, subscriber::Interest, Metadata};
+use tracing_subscriber::layer::{Context, Filter};
+
+use crate::{
+    fmt::{FieldValue, FmtFields},
+    EventKind, KindFilter, SpanKind,
+};
+
+/// The environment variable read by [`Directives::from_env`].
+pub const DEFAULT_ENV: &str = "ARI_LOG";
+
+/// Filtering directives, parsed from a string such as the value of the `ARI_LOG` environment
+/// variable.
+///
+/// Set with [`Builder::with_directives`]. The string is a comma separated list of directives,
+/// each of which is one of the following:
+///
+/// - A kind of Tokio instrumentation to select: `tasks`, `resources`, `async_ops`,
+///   `async_op_polls`, `wakers`, `poll_ops`, `state_updates`, or `other` (the spans and events
+///   which aren't Tokio instrumentation). If any kinds are listed, only those kinds are selected.
+///   A kind prefixed with `-` is excluded instead, e.g. `-async_op_polls`.
+/// - `resource=<concrete type>`, which selects only the resources of a type, such as
+///   `resource=Semaphore`, and selects `resources` if kinds are listed. The async ops on other
+///   resources are also left out.
+/// - `loc=<glob>`, which selects only the tasks spawned in a file matching the glob, such as
+///   `loc=src/net/**`, and selects `tasks` if kinds are listed. In the glob, `*` matches
+///   anything but `/`, `**` matches anything including `/`, and `?` matches any single character
+///   but `/`. The glob is matched against the task span's `loc.file` field.
+/// - `level=<level>`, or just `<level>`, the most verbose level of the spans and events which
+///   aren't Tokio instrumentation, such as `level=debug`.
+/// - `<target>=<level>`, or just `<target>` for all levels, the most verbose level of the spans
+///   and events which aren't Tokio instrumentation and have this target or a target within it,
+///   such as `my_crate::net=trace`.
+///
+/// `resource` and `loc` can be given more than once to select more types or locations. Spans
+/// created within a resource, async op, or other span which isn't selected are left out too, as
+/// are the events within them.
+///
+/// Level and target directives, or the presence of `other`, select the spans and events which
+/// aren't Tokio instrumentation if kinds are listed. Tokio's instrumentation is emitted at
+/// `TRACE` level, so the level directives don't apply to it.
+///
+/// [`Builder::with_directives`]: crate::Builder::with_directives
+///
+/// # Examples
+///
+/// ```rust
+/// use ari_subscriber::{Directives, Layer};
+///
+/// let directives: Directives = "tasks,wakers,resource=Semaphore,loc=src/net/**,level=debug"
+///     .parse()
+///     .unwrap();
+/// let layer = Layer::builder().with_directives(directives).build();
+/// ```
+///
+/// Directives are also a per-layer [`Filter`], which can be added to the layer with
+/// [`Layer::with_filter`]. Then the callsites of spans and events which the kind, level, and
+/// target directives don't select are disabled for ari's layer when they are registered, while
+/// other layers in the same registry still receive them. The resource type and spawn location
+/// directives depend on the fields of each span, so they are only applied by the layer itself,
+/// when set with [`Builder::with_directives`].
+///
+/// [`Layer::with_filter`]: tracing_subscriber::Layer::with_filter
+///
+/// ```rust
+/// use tracing_subscriber::prelude::*;
+///
+/// use ari_subscriber::Directives;
+///
+/// let directives = Directives::parse("tasks,wakers").unwrap();
+/// tracing_subscriber::registry()
+///     .with(ari_subscriber::layer().with_filter(directives))
+///     .init();
+/// ```
+///
+/// Reading the directives from the `ARI_LOG` environment variable:
+///
+/// ```rust
+/// use ari_subscriber::{Directives, Layer};
+///
+/// let directives = Directives::from_env().expect("ARI_LOG is invalid");
+/// let layer = Layer::builder().with_directives(directives).build();
+/// ```
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Directives {
+    /// The kind filter, if any kinds were listed.
+    kind_filter: Option<KindFilter>,
+    resource_types: Vec<String>,
+    spawn_locations: Vec<String>,
+    level: Option<LevelFilter>,
+    targets: Vec<(String, LevelFilter)>,
+}
+
+/// An error parsing [`Directives`].
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ParseError {
+    directive: String,
+    kind: ParseErrorKind,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+enum ParseErrorKind {
+    MissingValue,
+    InvalidLevel(String),
+    InvalidTarget,
+    ExcludedNonKind,
+}
+
+impl Directives {
+    /// Parses directives from a string.
+    ///
+    /// This is the same as using [`str::parse`].
+    pub fn parse(directives: &str) -> Result<Self, ParseError> {
+        directives.parse()
+    }
+
+    /// Parses directives from the `ARI_LOG` environment variable.
+    ///
+    /// If the variable isn't set, the directives are empty and everything is selected.
+    pub fn from_env() -> Result<Self, ParseError> {
+        Self::from_env_var(DEFAULT_ENV)
+    }
+
+    /// Parses directives from the given environment variable.
+    ///
+    /// If the variable isn't set, the directives are empty and everything is selected.
+    pub fn from_env_var(name: &str) -> Result<Self, ParseError> {
+        match env::var(name) {
+            Ok(directives) => directives.parse(),
+            Err(_) => Ok(Self::default()),
+        }
+    }
+
+    /// Returns the kind filter, if any kinds were listed.
+    pub(crate) fn kind_filter(&self) -> Option<&KindFilter> {
+        self.kind_filter.as_ref()
+    }
+
+    /// Returns `true` if the level and target directives select the span or event with this
+    /// metadata, they only apply to spans and events which aren't Tokio instrumentation.
+    pub(crate) fn enabled(&self, meta: &Metadata<'_>) -> bool {
+        let is_other = if meta.is_span() {
+            SpanKind::from_metadata(meta) == SpanKind::Unknown
+        } else {
+            EventKind::from_metadata(meta) == EventKind::Unknown
+        };
+        !is_other || self.level_for(meta.target()) >= *meta.level()
+    }
+
+    /// Returns `true` if the kind, level, and target directives select the span or event with
+    /// this metadata.
+    fn selects(&self, meta: &Metadata<'_>) -> bool {
+        self.kind_filter
+            .as_ref()
+            .map_or(true, |kind_filter| kind_filter.enabled(meta))
+            && self.enabled(meta)
+    }
+
+    /// Returns the most verbose level selected for a target, from the directive for the longest
+    /// matching target, or else the `level` directive.
+    fn level_for(&self, target: &str) -> LevelFilter {
+        self.targets
+            .iter()
+            .filter(|(directive, _)| target_matches(target, directive))
+            .max_by_key(|(directive, _)| directive.len())
+            .map(|(_, level)| *level)
+            .or(self.level)
+            .unwrap_or(LevelFilter::TRACE)
+    }
+
+    /// Returns `true` if the resource type and spawn location directives select a new span.
+    pub(crate) fn span_selected(&self, kind: &SpanKind, fields: &FmtFields) -> bool {
+        let field = |name| fields.get(name).and_then(FieldValue::as_str);
+        match kind {
+            SpanKind::Resource if !self.resource_types.is_empty() => {
+                let concrete_type = field("concrete_type").unwrap_or_default();
+                self.resource_types.iter().any(|ty| ty == concrete_type)
+            }
+            SpanKind::Spawn if !self.spawn_locations.is_empty() => {
+                let file = field("loc.file").unwrap_or_default();
+                self.spawn_locations
+                    .iter()
+                    .any(|glob| glob_matches(glob, file))
+            }
+            _ => true,
+        }
+    }
+}
+
+impl<S> Filter<S> for Directives {
+    fn enabled(&self, meta: &Metadata<'_>, _cx: &Context<'_, S>) -> bool {
+        self.selects(meta)
+    }
+
+    fn callsite_enabled(&self, meta: &'static Metadata<'static>) -> Interest {
+        if self.selects(meta) {
+            Interest::always()
+        } else {
+            Interest::never()
+        }
+    }
+}
+
+impl FromStr for Directives {
+    type Err = ParseError;
+
+    fn from_str(directives: &str) -> Result<Self, Self::Err> {
+        let mut parsed = Self::default();
+        let mut lists_kinds = false;
+        // The kinds listed, or selected implicitly by other directives.
+        let mut includes = Vec::new();
+        let mut excludes = Vec::new();
+
+        for directive in directives.split(',').map(str::trim) {
+            if directive.is_empty() {
+                continue;
+            }
+            let error = |kind| ParseError {
+                directive: directive.to_owned(),
+                kind,
+            };
+
+            if let Some((key, value)) = directive.split_once('=') {
+                let (key, value) = (key.trim(), value.trim());
+                if value.is_empty() {
+                    return Err(error(ParseErrorKind::MissingValue));
+                }
+                match key {
+                    "resource" => {
+                        parsed.resource_types.push(value.to_owned());
+                        includes.push(Kind::Resources);
+                    }
+                    "loc" => {
+                        parsed.spawn_locations.push(value.to_owned());
+                        includes.push(Kind::Tasks);
+                    }
+                    "level" => {
+                        parsed.level = Some(parse_level(value).map_err(error)?);
+                        includes.push(Kind::Other);
+                    }
+                    target => {
+                        if !is_valid_target(target) {
+                            return Err(error(ParseErrorKind::InvalidTarget));
+                        }
+                        let level = parse_level(value).map_err(error)?;
+                        parsed.targets.push((target.to_owned(), level));
+                        includes.push(Kind::Other);
+                    }
+                }
+            } else if let Some(excluded) = directive.strip_prefix('-') {
+                let kind = Kind::from_name(excluded.trim())
+                    .ok_or_else(|| error(ParseErrorKind::ExcludedNonKind))?;
+                excludes.push(kind);
+            } else if let Some(kind) = Kind::from_name(directive) {
+                lists_kinds = true;
+                includes.push(kind);
+            } else if let Ok(level) = directive.parse::<LevelFilter>() {
+                parsed.level = Some(level);
+                includes.push(Kind::Other);
+            } else if is_valid_target(directive) {
+                parsed
+                    .targets
+                    .push((directive.to_owned(), LevelFilter::TRACE));
+                includes.push(Kind::Other);
+            } else {
+                return Err(error(ParseErrorKind::InvalidTarget));
+            }
+        }
+
+        // Kinds selected implicitly by other directives only matter if kinds are listed.
+        if lists_kinds || !excludes.is_empty() {
+            let filter = if lists_kinds {
+                includes
+                    .iter()
+                    .fold(KindFilter::none(), |filter, kind| kind.include(filter))
+            } else {
+                KindFilter::all()
+            };
+            let filter = excludes
+                .iter()
+                .fold(filter, |filter, kind| kind.exclude(filter));
+            parsed.kind_filter = Some(filter);
+        }
+
+        Ok(parsed)
+    }
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "invalid directive `{}`: ", self.directive)?;
+        match &self.kind {
+            ParseErrorKind::MissingValue => f.write_str("missing value after `=`"),
+            ParseErrorKind::InvalidLevel(level) => write!(f, "`{level}` is not a level"),
+            ParseErrorKind::InvalidTarget => f.write_str("not a kind, level, or target"),
+            ParseErrorKind::ExcludedNonKind => f.write_str("only kinds can be excluded with `-`"),
+        }
+    }
+}
+
+impl Error for ParseError {}
+
+/// A kind of spans and events which can be listed in the directives.
+#[derive(Clone, Copy)]
+enum Kind {
+    Tasks,
+    Resources,
+    AsyncOps,
+    AsyncOpPolls,
+    Wakers,
+    PollOps,
+    StateUpdates,
+    Other,
+}
+
+impl Kind {
+    fn from_name(name: &str) -> Option<Self> {
+        Some(match name {
+            "tasks" => Self::Tasks,
+            "resources" => Self::Resources,
+            "async_ops" => Self::AsyncOps,
+            "async_op_polls" => Self::AsyncOpPolls,
+            "wakers" => Self::Wakers,
+            "poll_ops" => Self::PollOps,
+            "state_updates" => Self::StateUpdates,
+            "other" => Self::Other,
+            _ => return None,
+        })
+    }
+
+    fn include(self, filter: KindFilter) -> KindFilter {
+        match self {
+            Self::Tasks => filter.include_span(SpanKind::Spawn),
+            Self::Resources => filter.include_span(SpanKind::Resource),
+            Self::AsyncOps => filter.include_span(SpanKind::AsyncOp),
+            Self::AsyncOpPolls => filter.include_span(SpanKind::AsyncOpPoll),
+            Self::Wakers => filter.include_event(EventKind::Waker),
+            Self::PollOps => filter.include_event(EventKind::PollOp),
+            Self::StateUpdates => filter
+                .include_event(EventKind::ResourceStateUpdate)
+                .include_event(EventKind::AsyncOpUpdate),
+            Self::Other => filter
+                .include_span(SpanKind::Unknown)
+                .include_event(EventKind::Unknown),
+        }
+    }
+
+    fn exclude(self, filter: KindFilter) -> KindFilter {
+        match self {
+            Self::Tasks => filter.exclude_span(SpanKind::Spawn),
+            Self::Resources => filter.exclude_span(SpanKind::Resource),
+            Self::AsyncOps => filter.exclude_span(SpanKind::AsyncOp),
+            Self::AsyncOpPolls => filter.exclude_span(SpanKind::AsyncOpPoll),
+            Self::Wakers => filter.exclude_event(EventKind::Waker),
+            Self::PollOps => filter.exclude_event(EventKind::PollOp),
+            Self::StateUpdates => filter
+                .exclude_event(EventKind::ResourceStateUpdate)
+                .exclude_event(EventKind::AsyncOpUpdate),
+            Self::Other => filter
+                .exclude_span(SpanKind::Unknown)
+                .exclude_event(EventKind::Unknown),
+        }
+    }
+}
+
+fn parse_level(level: &str) -> Result<LevelFilter, ParseErrorKind> {
+    level
+        .parse()
+        .map_err(|_| ParseErrorKind::InvalidLevel(level.to_owned()))
+}
+
+fn is_valid_target(target: &str) -> bool {
+    !target.is_empty()
+        && target
+            .chars()
+            .all(|c| c.is_alphanumeric() || matches!(c, '_' | '-' | ':'))
+}
+
+/// Returns `true` if `target` is `directive` or a target within it, e.g. `my_crate::net`
+/// matches `my_crate::net::server`.
+fn target_matches(target: &str, directive: &str) -> bool {
+    target
+        .strip_prefix(directive)
+        .map_or(false, |rest| rest.is_empty() || rest.starts_with("::"))
+}
+
+/// Matches a path against a glob, see [`Directives`] for the syntax.
+///
+/// The glob is matched from left to right, and on a mismatch only the last `*` and the last `**`
+/// are given more of the path, so the time taken is at most proportional to the product of the
+/// lengths of the glob and the path.
+fn glob_matches(glob: &str, path: &str) -> bool {
+    let glob = glob.chars().collect::<Vec<_>>();
+    let path = path.chars().collect::<Vec<_>>();
+    let (mut g, mut p) = (0, 0);
+    // For the last `*`, the position in the glob after it and the end of what it has matched.
+    let mut star: Option<(usize, usize)> = None;
+    // The same for the last `**`, and whether it is followed by `/`.
+    let mut double_star: Option<(usize, usize, bool)> = None;
+
+    loop {
+        match glob.get(g) {
+            Some('*') if glob.get(g + 1) == Some(&'*') => {
+                let dirs = glob.get(g + 2) == Some(&'/');
+                g += if dirs { 3 } else { 2 };
+                double_star = Some((g, p, dirs));
+                star = None;
+                continue;
+            }
+            Some('*') => {
+                g += 1;
+                star = Some((g, p));
+                continue;
+            }
+            Some('?') if path.get(p).map_or(false, |c| *c != '/') => {
+                g += 1;
+                p += 1;
+                continue;
+            }
+            Some(c) if *c != '?' && path.get(p) == Some(c) => {
+                g += 1;
+                p += 1;
+                continue;
+            }
+            None if p == path.len() => return true,
+            _ => {}
+        }
+
+        // A `*` can match one more character, as long as it stays within a single directory.
+        if let Some((after, end)) = star {
+            if path.get(end).map_or(false, |c| *c != '/') {
+                star = Some((after, end + 1));
+                (g, p) = (after, end + 1);
+                continue;
+            }
+        }
+        // `**/` matches whole directories, e.g. `src/**/mod.rs` matches `src/mod.rs` and
+        // `src/net/mod.rs`, while `**` can match one more character of anything.
+        if let Some((after, end, dirs)) = double_star {
+            let next = if dirs {
+                path[end..]
+                    .iter()
+                    .position(|c| *c == '/')
+                    .map(|i| end + i + 1)
+            } else {
+                (end < path.len()).then_some(end + 1)
+            };
+            if let Some(next) = next {
+                double_star = Some((after, next, dirs));
+                star = None;
+                (g, p) = (after, next);
+                continue;
+            }
+        }
+        return false;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn glob_matching() {
+        assert!(glob_matches("src/main.rs", "src/main.rs"));
+        assert!(!glob_matches("src/main.rs", "src/main.rs.bak"));
+        assert!(glob_matches("src/*.rs", "src/main.rs"));
+        assert!(!glob_matches("src/*.rs", "src/net/mod.rs"));
+        assert!(glob_matches("src/*/*.rs", "src/net/mod.rs"));
+        assert!(glob_matches("src/m??n.rs", "src/main.rs"));
+        assert!(!glob_matches("src?main.rs", "src/main.rs"));
+        assert!(glob_matches("src/**", "src/net/tcp/mod.rs"));
+        assert!(glob_matches("**", ""));
+        assert!(glob_matches("**/mod.rs", "mod.rs"));
+        assert!(glob_matches("src/**/mod.rs", "src/mod.rs"));
+        assert!(glob_matches("src/**/mod.rs", "src/net/tcp/mod.rs"));
+        assert!(!glob_matches("src/**/mod.rs", "src/net/amod.rs"));
+        assert!(glob_matches("src/**.rs", "src/net/mod.rs"));
+        assert!(glob_matches("**/net/*.rs", "crates/app/src/net/tcp.rs"));
+        assert!(!glob_matches(
+            "**/net/*.rs",
+            "crates/app/src/net/tcp/mod.rs"
+        ));
+    }
+
+    #[test]
+    fn glob_matching_is_not_exponential() {
+        let path = "a".repeat(64);
+        assert!(!glob_matches(&format!("{}b", "a*".repeat(32)), &path));
+        assert!(!glob_matches(&format!("{}b", "**a".repeat(32)), &path));
+        assert!(glob_matches(&"a*".repeat(32), &path));
+    }
+
+    #[test]
+    fn malformed_directives() {
+        let error_kind = |directives: &str| Directives::parse(directives).unwrap_err().kind;
+
+        assert_eq!(error_kind("tasks,resource="), ParseErrorKind::MissingValue);
+        assert_eq!(
+            error_kind("level=loud"),
+            ParseErrorKind::InvalidLevel("loud".into())
+        );
+        assert_eq!(
+            error_kind("my_crate=loud"),
+            ParseErrorKind::InvalidLevel("loud".into())
+        );
+        assert_eq!(error_kind("my crate"), ParseErrorKind::InvalidTarget);
+        assert_eq!(error_kind("my crate=info"), ParseErrorKind::InvalidTarget);
+        assert_eq!(error_kind("-info"), ParseErrorKind::ExcludedNonKind);
+
+        assert_eq!(
+            Directives::parse("tasks, resource=")
+                .unwrap_err()
+                .to_string(),
+            "invalid directive `resource=`: missing value after `=`",
+        );
+    }
+
+    #[test]
+    fn excluded_kinds() {
+        let directives = Directives::parse("-async_op_polls,-state_updates").unwrap();
+        assert_eq!(
+            directives.kind_filter(),
+            Some(
+                &KindFilter::all()
+                    .exclude_span(SpanKind::AsyncOpPoll)
+                    .exclude_event(EventKind::ResourceStateUpdate)
+                    .exclude_event(EventKind::AsyncOpUpdate)
+            ),
+        );
+
+        // An excluded kind wins over the same kind being listed.
+        let directives = Directives::parse("tasks,wakers,-wakers").unwrap();
+        assert_eq!(
+            directives.kind_filter(),
+            Some(&KindFilter::none().include_span(SpanKind::Spawn)),
+        );
+    }
+
+    #[test]
+    fn implicitly_selected_kinds() {
+        // Without listed kinds, the other directives don't restrict the kinds.
+        let directives = Directives::parse("resource=Semaphore,level=info").unwrap();
+        assert_eq!(directives.kind_filter(), None);
+
+        let directives = Directives::parse("wakers,resource=Semaphore,level=info").unwrap();
+        assert_eq!(
+            directives.kind_filter(),
+            Some(
+                &KindFilter::none()
+                    .include_event(EventKind::Waker)
+                    .include_span(SpanKind::Resource)
+                    .include_span(SpanKind::Unknown)
+                    .include_event(EventKind::Unknown)
+            ),
+        );
+    }
+
+    #[test]
+    fn level_precedence() {
+        let directives = Directives::parse("my_crate=info,warn,my_crate::net=trace").unwrap();
+
+        // The longest matching target wins, wherever it is in the list.
+        assert_eq!(directives.level_for("my_crate::net"), LevelFilter::TRACE);
+        assert_eq!(
+            directives.level_for("my_crate::net::tcp"),
+            LevelFilter::TRACE
+        );
+        assert_eq!(directives.level_for("my_crate::db"), LevelFilter::INFO);
+        assert_eq!(directives.level_for("my_crate"), LevelFilter::INFO);
+        // A target only matches whole path segments.
+        assert_eq!(directives.level_for("my_crate_utils"), LevelFilter::WARN);
+        assert_eq!(directives.level_for("other"), LevelFilter::WARN);
+
+        // A later level directive replaces an earlier one.
+        let directives = Directives::parse("level=debug,error").unwrap();
+        assert_eq!(directives.level_for("other"), LevelFilter::ERROR);
+
+        // Without a level directive, everything is selected.
+        let directives = Directives::parse("my_crate=info").unwrap();
+        assert_eq!(directives.level_for("other"), LevelFilter::TRACE);
+    }
+}
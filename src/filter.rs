@@ -0,0 +1,169 @@
+//! Filtering by ari's classification of spans and events.
+//!
+//! See the documentation on [`KindFilter`] for more details.
+use tracing::{subscriber::Interest, Metadata};
+use tracing_subscriber::layer::{Context, Filter};
+
+use crate::{EventKind, SpanKind};
+
+/// The span kinds which can be selected by a [`KindFilter`].
+const SPAN_KINDS: [SpanKind; 5] = [
+    SpanKind::Unknown,
+    SpanKind::Spawn,
+    SpanKind::Resource,
+    SpanKind::AsyncOp,
+    SpanKind::AsyncOpPoll,
+];
+
+/// The event kinds which can be selected by a [`KindFilter`].
+const EVENT_KINDS: [EventKind; 5] = [
+    EventKind::Unknown,
+    EventKind::Waker,
+    EventKind::PollOp,
+    EventKind::ResourceStateUpdate,
+    EventKind::AsyncOpUpdate,
+];
+
+/// Selects spans and events by their [`SpanKind`] and [`EventKind`].
+///
+/// Set with [`Builder::with_kind_filter`]. A filter starts with either all kinds selected
+/// ([`KindFilter::all`]) or none of them ([`KindFilter::none`]), and kinds are then included or
+/// excluded individually.
+///
+/// Only the kinds of spans and events recorded through `tracing` can be selected, the span
+/// lifecycle messages of the selected spans and the warnings generated by ari are always written.
+///
+/// Lost waker detection and scheduling latencies rely on Tokio's waker events, so they don't
+/// work if [`EventKind::Waker`] isn't selected.
+///
+/// Spans and events which aren't selected are ignored by ari, but a filter set on the builder
+/// doesn't disable their callsites, so other layers in the same registry, such as
+/// `console-subscriber`, still receive them. Spans which aren't selected are left out of the
+/// scope of the lines which are written.
+///
+/// A `KindFilter` is also a per-layer [`Filter`], which can be added to the layer with
+/// [`Layer::with_filter`]. Then the callsites of spans and events which aren't selected are
+/// disabled for ari's layer when they are registered, while other layers in the same registry
+/// still receive them.
+///
+/// [`Layer::with_filter`]: tracing_subscriber::Layer::with_filter
+///
+/// [`Builder::with_kind_filter`]: crate::Builder::with_kind_filter
+///
+/// # Examples
+///
+/// ```rust
+/// use ari_subscriber::{EventKind, KindFilter, Layer, SpanKind};
+///
+/// // Only tasks and wakes.
+/// let filter = KindFilter::none()
+///     .include_span(SpanKind::Spawn)
+///     .include_event(EventKind::Waker);
+/// let layer = Layer::builder().with_kind_filter(filter).build();
+///
+/// // Everything except the polls of async ops.
+/// let filter = KindFilter::all().exclude_span(SpanKind::AsyncOpPoll);
+/// let layer = Layer::builder().with_kind_filter(filter).build();
+/// ```
+///
+/// As a per-layer filter:
+///
+/// ```rust
+/// use tracing_subscriber::prelude::*;
+///
+/// use ari_subscriber::{KindFilter, SpanKind};
+///
+/// let filter = KindFilter::none().include_span(SpanKind::Spawn);
+/// tracing_subscriber::registry()
+///     .with(ari_subscriber::layer().with_filter(filter))
+///     .init();
+/// ```
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct KindFilter {
+    spans: Vec<SpanKind>,
+    events: Vec<EventKind>,
+}
+
+impl KindFilter {
+    /// Selects all spans and events, which is the default.
+    #[must_use]
+    pub fn all() -> Self {
+        Self {
+            spans: SPAN_KINDS.to_vec(),
+            events: EVENT_KINDS.to_vec(),
+        }
+    }
+
+    /// Selects no spans or events, kinds must then be included individually.
+    #[must_use]
+    pub fn none() -> Self {
+        Self {
+            spans: Vec::new(),
+            events: Vec::new(),
+        }
+    }
+
+    /// Selects the spans of a kind.
+    #[must_use]
+    pub fn include_span(mut self, kind: SpanKind) -> Self {
+        if !self.spans.contains(&kind) {
+            self.spans.push(kind);
+        }
+        self
+    }
+
+    /// Stops selecting the spans of a kind.
+    #[must_use]
+    pub fn exclude_span(mut self, kind: SpanKind) -> Self {
+        self.spans.retain(|selected| *selected != kind);
+        self
+    }
+
+    /// Selects the events of a kind.
+    ///
+    /// [`EventKind::SpanEvent`] and [`EventKind::Warning`] are generated by ari and can't be
+    /// selected, including them has no effect.
+    #[must_use]
+    pub fn include_event(mut self, kind: EventKind) -> Self {
+        if EVENT_KINDS.contains(&kind) && !self.events.contains(&kind) {
+            self.events.push(kind);
+        }
+        self
+    }
+
+    /// Stops selecting the events of a kind.
+    #[must_use]
+    pub fn exclude_event(mut self, kind: EventKind) -> Self {
+        self.events.retain(|selected| *selected != kind);
+        self
+    }
+
+    /// Returns `true` if the span or event with this metadata is selected.
+    pub(crate) fn enabled(&self, meta: &Metadata<'_>) -> bool {
+        if meta.is_span() {
+            self.spans.contains(&SpanKind::from_metadata(meta))
+        } else {
+            self.events.contains(&EventKind::from_metadata(meta))
+        }
+    }
+}
+
+impl<S> Filter<S> for KindFilter {
+    fn enabled(&self, meta: &Metadata<'_>, _cx: &Context<'_, S>) -> bool {
+        KindFilter::enabled(self, meta)
+    }
+
+    fn callsite_enabled(&self, meta: &'static Metadata<'static>) -> Interest {
+        if KindFilter::enabled(self, meta) {
+            Interest::always()
+        } else {
+            Interest::never()
+        }
+    }
+}
+
+impl Default for KindFilter {
+    fn default() -> Self {
+        Self::all()
+    }
+}
@@ -0,0 +1,313 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use chrono::{DateTime, Utc};
+use serde_json::{json, Value};
+
+use super::{FmtEvent, FmtSpan, SpanKind};
+
+/// The process which contains a track for each task.
+const TASKS_PID: u64 = 1;
+
+/// The process which contains a track for each thread, for trace events which don't occur
+/// within a task.
+const THREADS_PID: u64 = 2;
+
+/// The next thread id to assign, shared by all traces in the process.
+static NEXT_THREAD_TID: AtomicU64 = AtomicU64::new(1);
+
+thread_local! {
+    /// The id of the current thread's track, assigned the first time it is needed.
+    static THREAD_TID: u64 = NEXT_THREAD_TID.fetch_add(1, Ordering::Relaxed);
+}
+
+/// The track which a trace event is shown on.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum Track {
+    /// The track of a task, identified by the displayed id of the task's span.
+    Task(u64),
+    /// The track of a thread, for trace events which don't occur within a task.
+    ///
+    /// Spans outside tasks are entered and exited on a single thread, so their slices nest
+    /// correctly on the thread's track.
+    Thread(u64),
+}
+
+impl Track {
+    /// Returns the track of the current thread.
+    pub(crate) fn current_thread() -> Self {
+        Self::Thread(THREAD_TID.with(|tid| *tid))
+    }
+
+    fn pid(self) -> u64 {
+        match self {
+            Self::Task(_) => TASKS_PID,
+            Self::Thread(_) => THREADS_PID,
+        }
+    }
+
+    fn tid(self) -> u64 {
+        match self {
+            Self::Task(tid) | Self::Thread(tid) => tid,
+        }
+    }
+}
+
+/// The state needed to write a trace in the [Chrome Trace Event Format].
+///
+/// The trace is written in the JSON Array Format, where each event is written on its own line
+/// followed by a comma. The closing `]` is optional in this format, which allows the trace to be
+/// streamed without knowing when the last event has been written.
+///
+/// Each Tokio task is represented as a thread (track) identified by the task span's id, each
+/// poll of a task is a duration slice on that track and the resources and async ops entered
+/// while polling are nested slices. Wakes are represented as flow events from the waking track
+/// to the next poll of the woken task. Spans and events outside any task are shown on a track
+/// per thread, in a separate process.
+///
+/// [Chrome Trace Event Format]: https://docs.google.com/document/d/1CvAClvFfyA5R-PhYUmn5OOQtYMH4h6I0nSsKchNAySU
+pub(crate) struct ChromeTrace {
+    start: DateTime<Utc>,
+    next_flow_id: AtomicU64,
+}
+
+/// The flows which end at the next poll of a task.
+///
+/// Stored in the extensions of the task's span.
+#[derive(Default)]
+pub(crate) struct PendingFlows(Vec<u64>);
+
+impl ChromeTrace {
+    pub(crate) fn new(start: DateTime<Utc>) -> Self {
+        Self {
+            start,
+            next_flow_id: AtomicU64::new(1),
+        }
+    }
+
+    /// Returns the beginning of the trace, the opening `[` followed by the lines which name the
+    /// processes containing the task and thread tracks.
+    ///
+    /// The header must be written before any other line.
+    pub(crate) fn header() -> String {
+        let process_name = |pid: u64, name: &str| {
+            Self::line(&json!({
+                "name": "process_name",
+                "ph": "M",
+                "pid": pid,
+                "args": { "name": name },
+            }))
+        };
+        format!(
+            "[\n{tasks}\n{threads}\n",
+            tasks = process_name(TASKS_PID, "tasks"),
+            threads = process_name(THREADS_PID, "threads"),
+        )
+    }
+
+    /// Formats a trace event as a line of output.
+    pub(crate) fn line(event: &Value) -> String {
+        format!("{event},")
+    }
+
+    /// Returns the metadata event which names the track for a task.
+    pub(crate) fn track_name(span: &FmtSpan) -> Value {
+        let task_id = span
+            .fields
+            .get("task.id")
+            .map(ToString::to_string)
+            .unwrap_or_default();
+        let mut name = match span.fields.get("task.name").and_then(|name| name.as_str()) {
+            Some(task_name) if !task_name.is_empty() => format!("{task_name} (task {task_id})"),
+            _ => format!("task {task_id}"),
+        };
+        if let Some(location) = span.location() {
+            name.push_str(&format!(" {location}"));
+        }
+
+        json!({
+            "name": "thread_name",
+            "ph": "M",
+            "pid": TASKS_PID,
+            "tid": span.display_id(),
+            "args": { "name": name },
+        })
+    }
+
+    /// Returns the beginning (`B`) or end (`E`) of a duration slice for a span.
+    pub(crate) fn slice(
+        &self,
+        phase: &str,
+        timestamp: DateTime<Utc>,
+        span: &FmtSpan,
+        track: Track,
+    ) -> Value {
+        let name = match span.kind {
+            SpanKind::Spawn => "poll".to_owned(),
+            _ => span
+                .fields
+                .get("concrete_type")
+                .or_else(|| span.fields.get("source"))
+                .and_then(|value| value.as_str())
+                .map_or_else(|| span.name.clone(), ToOwned::to_owned),
+        };
+
+        json!({
+            "name": name,
+            "cat": span.kind.name(),
+            "ph": phase,
+            "ts": self.ts(timestamp),
+            "pid": track.pid(),
+            "tid": track.tid(),
+            "args": span.fields.json(),
+        })
+    }
+
+    /// Returns an instant event for an event.
+    pub(crate) fn instant(&self, event: &FmtEvent<'_>, track: Track) -> Value {
+        json!({
+            "name": event.meta.target(),
+            "cat": event.kind.name(),
+            "ph": "i",
+            "s": "t",
+            "ts": self.ts(event.timestamp),
+            "pid": track.pid(),
+            "tid": track.tid(),
+            "args": event.fields.json(),
+        })
+    }
+
+    /// Returns the start of a new flow, the id of the flow must be passed to [`Self::flow_end`].
+    pub(crate) fn flow_start(&self, timestamp: DateTime<Utc>, track: Track) -> (u64, Value) {
+        let id = self.next_flow_id.fetch_add(1, Ordering::Relaxed);
+        let event = json!({
+            "name": "wake",
+            "cat": "waker",
+            "ph": "s",
+            "id": id,
+            "ts": self.ts(timestamp),
+            "pid": track.pid(),
+            "tid": track.tid(),
+        });
+
+        (id, event)
+    }
+
+    /// Returns the end of a flow, bound to the enclosing slice.
+    pub(crate) fn flow_end(&self, id: u64, timestamp: DateTime<Utc>, track: Track) -> Value {
+        json!({
+            "name": "wake",
+            "cat": "waker",
+            "ph": "f",
+            "bp": "e",
+            "id": id,
+            "ts": self.ts(timestamp),
+            "pid": track.pid(),
+            "tid": track.tid(),
+        })
+    }
+
+    /// Returns the timestamp in microseconds since the start of the trace.
+    fn ts(&self, timestamp: DateTime<Utc>) -> f64 {
+        // A trace would need to run for over 100 days to overflow nanoseconds.
+        #[allow(clippy::cast_precision_loss)]
+        let nanos = (timestamp - self.start)
+            .num_nanoseconds()
+            .unwrap_or_default() as f64;
+        nanos / 1_000.0
+    }
+}
+
+impl PendingFlows {
+    pub(crate) fn push(&mut self, id: u64) {
+        self.0.push(id);
+    }
+
+    pub(crate) fn take(&mut self) -> Vec<u64> {
+        std::mem::take(&mut self.0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{collections::HashMap, time::Duration};
+
+    use serde_json::Value;
+    use tracing_subscriber::prelude::*;
+
+    use crate::{
+        test_support::{run, Output},
+        Format, Layer,
+    };
+
+    /// Parses a streamed trace, which may leave out the closing `]`.
+    fn parse(output: &Output) -> Vec<Value> {
+        let contents = output.contents();
+        let trace = format!("{}]", contents.trim_end().trim_end_matches(','));
+        serde_json::from_str(&trace).expect("trace should be a valid JSON array")
+    }
+
+    fn trace() -> Vec<Value> {
+        let output = Output::default();
+        let layer = Layer::builder()
+            .with_writer(output.clone())
+            .with_format(Format::ChromeTrace)
+            .build();
+        run(tracing_subscriber::registry().with(layer), async {
+            // The sleep is created outside the task which polls it.
+            let sleep = tokio::time::sleep(Duration::from_millis(1));
+            tokio::task::Builder::new()
+                .name("sleeper")
+                .spawn(sleep)
+                .unwrap()
+                .await
+                .unwrap();
+        });
+        parse(&output)
+    }
+
+    #[test]
+    fn slices_pair_up_per_track() {
+        let mut open: HashMap<(u64, u64), Vec<String>> = HashMap::new();
+        for event in trace() {
+            let track = (
+                event["pid"].as_u64().unwrap(),
+                event["tid"].as_u64().unwrap_or(0),
+            );
+            let name = event["name"].as_str().unwrap().to_owned();
+            match event["ph"].as_str().unwrap() {
+                "B" => open.entry(track).or_default().push(name),
+                "E" => {
+                    let begun = open.get_mut(&track).and_then(Vec::pop);
+                    assert_eq!(begun, Some(name), "unmatched end on track {track:?}");
+                }
+                _ => {}
+            }
+        }
+        assert!(open.values().all(Vec::is_empty), "unended slices: {open:?}");
+    }
+
+    #[test]
+    fn resources_are_on_the_track_of_the_polling_task() {
+        let trace = trace();
+        let sleeper = trace
+            .iter()
+            .find(|event| {
+                event["name"] == "thread_name"
+                    && event["args"]["name"]
+                        .as_str()
+                        .map_or(false, |name| name.starts_with("sleeper"))
+            })
+            .expect("sleeper task should have a named track")["tid"]
+            .clone();
+
+        let sleep_slices = trace
+            .iter()
+            .filter(|event| event["ph"] == "B" && event["name"] == "Sleep")
+            .collect::<Vec<_>>();
+        // The sleep is entered once when it is created, the other times it is polled.
+        let (created, polled) = sleep_slices.split_first().unwrap();
+        assert_ne!(created["tid"], sleeper);
+        assert!(!polled.is_empty());
+        assert!(polled.iter().all(|slice| slice["tid"] == sleeper));
+    }
+}
@@ -1,4 +1,4 @@
-use colored::Color;
+use colored::{Color, ColoredString, Colorize};
 
 pub(super) const RED: Color = Color::TrueColor {
     r: 0xBA,
@@ -82,3 +82,150 @@ pub(super) const TURQUOISE_BOLD: Color = Color::TrueColor {
     g: 0xd8,
     b: 0xd6,
 };
+pub(super) const AMBER: Color = Color::TrueColor {
+    r: 0xff,
+    g: 0xb7,
+    b: 0x03,
+};
+pub(super) const AMBER_BOLD: Color = Color::TrueColor {
+    r: 0xfb,
+    g: 0x85,
+    b: 0x00,
+};
+
+/// The hues of consecutive task ids are this many degrees apart, the golden angle, so that tasks
+/// created around the same time have clearly different colors.
+const TASK_HUE_STEP: f64 = 137.508;
+
+/// The colors of a task span with [`Palette::Task`], with a hue derived from its id.
+///
+/// [`Palette::Task`]: super::Palette::Task
+pub(super) fn task_colors(id: u64) -> (Color, Color) {
+    let hue = (id as f64 * TASK_HUE_STEP) % 360.0;
+    (hsl(hue, 0.5, 0.5), hsl(hue, 0.7, 0.6))
+}
+
+/// A lighter version of a task's colors, for the spans and events within the task.
+pub(super) fn tint((color, bold): (Color, Color)) -> (Color, Color) {
+    (towards_white(color), towards_white(bold))
+}
+
+/// Mixes a color with white, leaving colors which aren't true colors unchanged.
+fn towards_white(color: Color) -> Color {
+    const WHITE_FRACTION: f64 = 0.4;
+    let Color::TrueColor { r, g, b } = color else {
+        return color;
+    };
+    let mix = |channel: u8| {
+        (f64::from(channel) + (255.0 - f64::from(channel)) * WHITE_FRACTION).round() as u8
+    };
+    Color::TrueColor {
+        r: mix(r),
+        g: mix(g),
+        b: mix(b),
+    }
+}
+
+/// Converts a color from HSL, with the hue in degrees and the saturation and lightness between
+/// 0 and 1.
+fn hsl(hue: f64, saturation: f64, lightness: f64) -> Color {
+    let chroma = (1.0 - (2.0 * lightness - 1.0).abs()) * saturation;
+    let sector = hue / 60.0;
+    let x = chroma * (1.0 - (sector % 2.0 - 1.0).abs());
+    let (r, g, b) = match sector as u8 {
+        0 => (chroma, x, 0.0),
+        1 => (x, chroma, 0.0),
+        2 => (0.0, chroma, x),
+        3 => (0.0, x, chroma),
+        4 => (x, 0.0, chroma),
+        _ => (chroma, 0.0, x),
+    };
+    let m = lightness - chroma / 2.0;
+    let channel = |value: f64| ((value + m) * 255.0).round() as u8;
+    Color::TrueColor {
+        r: channel(r),
+        g: channel(g),
+        b: channel(b),
+    }
+}
+
+/// The color of lines which are dimmed because they are outside the focus.
+pub(super) const GREY: Color = Color::TrueColor {
+    r: 0x6C,
+    g: 0x6C,
+    b: 0x6C,
+};
+
+/// Leaves a [`ColoredString`] styled only if ANSI escape codes are enabled for the layer.
+///
+/// Whether [`colored`] writes the escape codes of a styled string is decided by its process-wide
+/// override, which is set when the layer is built with ANSI escape codes enabled.
+///
+/// [`colored`]: https://docs.rs/colored
+pub(crate) trait Ansi {
+    fn ansi(self, enabled: bool) -> ColoredString;
+}
+
+impl Ansi for ColoredString {
+    fn ansi(self, enabled: bool) -> ColoredString {
+        if enabled {
+            self
+        } else {
+            self.clear()
+        }
+    }
+}
+
+/// Removes the ANSI escape codes from an already formatted string.
+pub(super) fn strip_ansi(formatted: &str) -> String {
+    let mut stripped = String::with_capacity(formatted.len());
+    let mut chars = formatted.chars();
+    while let Some(c) = chars.next() {
+        if c == '\x1b' {
+            // Skip the control sequence, up to and including its final byte.
+            for c in chars.by_ref() {
+                if ('\x40'..='\x7e').contains(&c) && c != '[' {
+                    break;
+                }
+            }
+        } else {
+            stripped.push(c);
+        }
+    }
+    stripped
+}
+
+#[cfg(test)]
+mod tests {
+    use colored::Color;
+
+    use super::{hsl, task_colors, tint};
+
+    fn rgb(r: u8, g: u8, b: u8) -> Color {
+        Color::TrueColor { r, g, b }
+    }
+
+    #[test]
+    fn hsl_primaries() {
+        assert_eq!(hsl(0.0, 1.0, 0.5), rgb(255, 0, 0));
+        assert_eq!(hsl(120.0, 1.0, 0.5), rgb(0, 255, 0));
+        assert_eq!(hsl(240.0, 1.0, 0.5), rgb(0, 0, 255));
+        assert_eq!(hsl(60.0, 0.0, 0.5), rgb(128, 128, 128));
+    }
+
+    #[test]
+    fn task_hues_do_not_repeat_every_360_ids() {
+        assert_ne!(task_colors(1), task_colors(2));
+        assert_ne!(task_colors(1), task_colors(361));
+        assert_ne!(task_colors(100), task_colors(460));
+    }
+
+    #[test]
+    fn tints_are_lighter() {
+        assert_eq!(
+            tint((rgb(0, 0, 0), rgb(255, 0, 100))),
+            (rgb(102, 102, 102), rgb(255, 102, 162))
+        );
+        assert_eq!(tint((Color::Red, Color::Blue)), (Color::Red, Color::Blue));
+    }
+}
@@ -0,0 +1,150 @@
+use std::{
+    collections::HashMap,
+    path::{Component, Path, PathBuf},
+    sync::Mutex,
+};
+
+use tracing::span;
+
+/// Rewrites the parts of the output which differ between runs of the same program: span ids
+/// and absolute paths.
+pub(crate) struct Deterministic {
+    span_ids: Mutex<SpanIds>,
+    current_dir: Option<PathBuf>,
+}
+
+/// Dense span ids, numbered from 1 in the order the spans first appear.
+#[derive(Default)]
+struct SpanIds {
+    ids: HashMap<span::Id, u64>,
+    next: u64,
+}
+
+impl SpanIds {
+    fn assign(&mut self, id: &span::Id) -> u64 {
+        self.next += 1;
+        self.ids.insert(id.clone(), self.next);
+        self.next
+    }
+}
+
+impl Deterministic {
+    pub(crate) fn new() -> Self {
+        Self {
+            span_ids: Mutex::default(),
+            current_dir: std::env::current_dir().ok(),
+        }
+    }
+
+    /// Assigns the next dense id to a new span.
+    ///
+    /// Span ids are reused by the registry once a span is closed, a new span with the same id
+    /// is given a new dense id.
+    pub(crate) fn new_span_id(&self, id: &span::Id) -> u64 {
+        self.span_ids().assign(id)
+    }
+
+    /// Forgets the dense id of a closed span, so that the map only holds the spans which are
+    /// open.
+    pub(crate) fn close_span(&self, id: &span::Id) {
+        self.span_ids().ids.remove(id);
+    }
+
+    /// Returns the dense id of a span, assigning one if the span hasn't been seen before (or has
+    /// already been closed).
+    pub(crate) fn span_id(&self, id: &span::Id) -> u64 {
+        let mut span_ids = self.span_ids();
+        match span_ids.ids.get(id) {
+            Some(dense) => *dense,
+            None => span_ids.assign(id),
+        }
+    }
+
+    /// Returns the path relative to the current directory or, for paths outside it (such as
+    /// dependencies in the Cargo registry), relative to the directory containing the crate's
+    /// `src` directory, e.g. `tokio-1.34.0/src/time/sleep.rs`.
+    ///
+    /// Returns `None` if the path is already relative.
+    pub(crate) fn relative_path(&self, path: &str) -> Option<String> {
+        let path = Path::new(path);
+        if !path.is_absolute() {
+            return None;
+        }
+        if let Some(relative) = self
+            .current_dir
+            .as_ref()
+            .and_then(|current_dir| path.strip_prefix(current_dir).ok())
+        {
+            return Some(relative.display().to_string());
+        }
+
+        let components = path.components().collect::<Vec<_>>();
+        let crate_dir = components
+            .iter()
+            .rposition(|component| *component == Component::Normal("src".as_ref()))
+            .filter(|src| *src > 0)
+            .map_or(components.len() - 1, |src| src - 1);
+        let relative = components[crate_dir..].iter().collect::<PathBuf>();
+        Some(relative.display().to_string())
+    }
+
+    fn span_ids(&self) -> std::sync::MutexGuard<'_, SpanIds> {
+        self.span_ids
+            .lock()
+            .unwrap_or_else(std::sync::PoisonError::into_inner)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tracing::span;
+    use tracing_subscriber::prelude::*;
+
+    use super::Deterministic;
+    use crate::{test_support::Output, Layer, Timestamp};
+
+    #[test]
+    fn closed_spans_are_forgotten() {
+        let deterministic = Deterministic::new();
+        let id = span::Id::from_u64(7);
+
+        assert_eq!(deterministic.new_span_id(&id), 1);
+        assert_eq!(deterministic.span_id(&id), 1);
+        deterministic.close_span(&id);
+        assert!(deterministic.span_ids().ids.is_empty());
+        assert_eq!(deterministic.span_id(&id), 2);
+    }
+
+    #[test]
+    fn golden_output() {
+        let output = Output::default();
+        let layer = Layer::builder()
+            .with_writer(output.clone())
+            .with_ansi(false)
+            .with_timestamp(Timestamp::Uptime)
+            .with_deterministic(true)
+            .build();
+        tracing::subscriber::with_default(tracing_subscriber::registry().with(layer), || {
+            for n in 1..=2 {
+                let span = tracing::info_span!("request", n);
+                let _guard = span.enter();
+                tracing::info!("handled");
+            }
+        });
+
+        // The registry reuses the id of the first span for the second, which is still given the
+        // next dense id.
+        let golden = "   0.000001s  INFO request[1]{n=1} new
+   0.000002s  INFO request[1]{n=1} enter
+   0.000003s  INFO request[1]{n=1} ari_subscriber::fmt::deterministic::tests: handled
+   0.000004s  INFO request[1]{n=1} exit
+   0.000005s  INFO request[1]{n=1} close
+   0.000006s  INFO request[2]{n=2} new
+   0.000007s  INFO request[2]{n=2} enter
+   0.000008s  INFO request[2]{n=2} ari_subscriber::fmt::deterministic::tests: handled
+   0.000009s  INFO request[2]{n=2} exit
+   0.000010s  INFO request[2]{n=2} close
+";
+        assert_eq!(output.contents(), golden);
+    }
+}
@@ -0,0 +1,152 @@
+use serde_json::{Map, Number, Value};
+
+use super::{EventKind, FieldValue, FmtEvent, FmtFields, FmtFieldsKind, FmtSpan};
+
+impl FmtEvent<'_> {
+    /// Formats the event as a single line JSON object.
+    pub(crate) fn json(&self, scope: Vec<Value>) -> String {
+        let mut object = Map::new();
+        object.insert(
+            "timestamp".into(),
+            self.timestamp
+                .format("%Y-%m-%dT%H:%M:%S%.6fZ")
+                .to_string()
+                .into(),
+        );
+        object.insert("level".into(), self.level.as_str().into());
+        object.insert("target".into(), self.meta.target().into());
+        object.insert("kind".into(), self.kind.name().into());
+        match &self.kind {
+            EventKind::SpanEvent(span_kind) => {
+                object.insert("span_kind".into(), span_kind.name().into());
+                self.insert_message(&mut object);
+                if let Some(readiness) = self.readiness {
+                    object.insert("readiness".into(), readiness.name().into());
+                }
+            }
+            EventKind::Warning(label) => {
+                object.insert("warning".into(), (*label).into());
+                self.insert_message(&mut object);
+            }
+            _ => {}
+        }
+        object.insert("scope".into(), Value::Array(scope));
+        object.insert("fields".into(), self.fields.json());
+
+        Value::Object(object).to_string()
+    }
+
+    fn insert_message(&self, object: &mut Map<String, Value>) {
+        if let FmtFieldsKind::Event(Some(message)) = &self.fields.kind {
+            object.insert("message".into(), message.as_str().into());
+        }
+    }
+}
+
+impl FmtSpan {
+    /// Returns the span as a JSON object for use in the scope of a JSON line.
+    pub(crate) fn json(&self) -> Value {
+        let mut object = Map::new();
+        object.insert("name".into(), self.name.as_str().into());
+        object.insert("id".into(), self.display_id.into());
+        object.insert("kind".into(), self.kind.name().into());
+        object.insert("fields".into(), self.fields.json());
+        if !self.follows.is_empty() {
+            let follows = self
+                .follows
+                .iter()
+                .map(|(_, id)| Value::from(*id))
+                .collect();
+            object.insert("follows".into(), Value::Array(follows));
+        }
+        if let Some(state) = &self.state {
+            object.insert("state".into(), state.json());
+        }
+
+        Value::Object(object)
+    }
+}
+
+impl FmtFields {
+    pub(super) fn json(&self) -> Value {
+        Value::Object(
+            self.fields
+                .iter()
+                .map(|(name, value)| (name.clone(), value.json()))
+                .collect(),
+        )
+    }
+}
+
+impl FieldValue {
+    pub(super) fn json(&self) -> Value {
+        match self {
+            Self::Bool(value) => Value::Bool(*value),
+            Self::I64(value) => Value::Number((*value).into()),
+            Self::U64(value) => Value::Number((*value).into()),
+            // Non-finite floats can't be represented in JSON.
+            Self::F64(value) => Number::from_f64(*value).map_or(Value::Null, Value::Number),
+            Self::Str(value) | Self::Debug(value) => Value::String(value.clone()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::Value;
+    use tracing::Instrument;
+    use tracing_subscriber::prelude::*;
+
+    use crate::{
+        test_support::{run, Output},
+        Format, Layer,
+    };
+
+    #[test]
+    fn lines_are_json_objects() {
+        let output = Output::default();
+        let layer = Layer::builder()
+            .with_writer(output.clone())
+            .with_format(Format::Json)
+            .build();
+        run(tracing_subscriber::registry().with(layer), async {
+            let span = tracing::info_span!("outer", quoted = "\"a\"\n", ratio = f64::NAN);
+            async {
+                tokio::spawn(tokio::time::sleep(std::time::Duration::from_millis(1)))
+                    .await
+                    .unwrap();
+                tracing::info!(answer = 42, "done");
+            }
+            .instrument(span)
+            .await;
+        });
+
+        let lines = output.lines();
+        assert!(!lines.is_empty());
+        for line in &lines {
+            let value: Value = serde_json::from_str(line).expect("line should be valid JSON");
+            assert!(value["kind"].is_string(), "{line}");
+            assert!(value["fields"].is_object(), "{line}");
+            for span in value["scope"].as_array().expect("scope should be an array") {
+                assert!(span["id"].is_u64(), "{line}");
+                assert!(span["kind"].is_string(), "{line}");
+                assert!(span["fields"].is_object(), "{line}");
+            }
+        }
+
+        let done = lines
+            .iter()
+            .map(|line| serde_json::from_str::<Value>(line).unwrap())
+            .find(|value| value["fields"]["answer"].is_number())
+            .unwrap();
+        assert_eq!(done["fields"]["answer"], 42);
+        let outer = done["scope"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .find(|span| span["name"] == "outer")
+            .unwrap();
+        assert_eq!(outer["fields"]["quoted"], "\"a\"\n");
+        assert_eq!(outer["fields"]["ratio"], Value::Null);
+    }
+}
@@ -1,28 +1,224 @@
+use std::{ops::BitOr, sync::Arc};
+
 use chrono::{DateTime, Utc};
 use colored::{Color, Colorize};
 use tracing::{field::Visit, span, Metadata};
 
+use crate::stats::{AsyncOpStats, TaskStats};
+use state::ResourceState;
+
+mod chrome;
 mod color;
+mod deterministic;
+mod json;
+mod state;
+pub(crate) use chrome::{ChromeTrace, PendingFlows, Track};
+pub(crate) use color::Ansi;
 use color::{
-    BLUE, BLUE_BOLD, GREEN, GREEN_BOLD, ORANGE, ORANGE_BOLD, PINK, PINK_BOLD, PURPLE, PURPLE_BOLD,
-    RED, RED_BOLD, TURQUOISE, TURQUOISE_BOLD, YELLOW, YELLOW_BOLD,
+    strip_ansi, task_colors, tint, AMBER, AMBER_BOLD, BLUE, BLUE_BOLD, GREEN, GREEN_BOLD, GREY,
+    ORANGE, ORANGE_BOLD, PINK, PINK_BOLD, PURPLE, PURPLE_BOLD, RED, RED_BOLD, TURQUOISE,
+    TURQUOISE_BOLD, YELLOW, YELLOW_BOLD,
 };
+pub(crate) use deterministic::Deterministic;
+
+/// The style in which the timestamp at the beginning of each line is displayed.
+///
+/// Set with [`Builder::with_timestamp`].
+///
+/// [`Builder::with_timestamp`]: crate::Builder::with_timestamp
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Timestamp {
+    /// The full date and time in UTC, e.g. `2023-11-28T10:06:44.746508Z`.
+    #[default]
+    DateTime,
+    /// Only the time in UTC, e.g. `10:06:44.746508`.
+    Time,
+    /// The time elapsed since the layer was created, e.g. `   0.000602s`.
+    Uptime,
+    /// No timestamp is displayed.
+    None,
+}
+
+/// The span lifecycle messages which are printed.
+///
+/// Values can be combined with `|`. Set with [`Builder::with_span_events`].
+///
+/// [`Builder::with_span_events`]: crate::Builder::with_span_events
+///
+/// # Examples
+///
+/// ```rust
+/// use ari_subscriber::SpanEvents;
+///
+/// let span_events = SpanEvents::NEW | SpanEvents::CLOSE;
+/// assert!(span_events.contains(SpanEvents::NEW));
+/// assert!(!span_events.contains(SpanEvents::ENTER));
+/// ```
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct SpanEvents(u8);
+
+impl SpanEvents {
+    /// No span lifecycle messages are printed.
+    pub const NONE: Self = Self(0);
+    /// A `new` message is printed when a span is created.
+    pub const NEW: Self = Self(1 << 0);
+    /// An `enter` message is printed when a span is entered.
+    pub const ENTER: Self = Self(1 << 1);
+    /// An `exit` message is printed when a span is exited.
+    pub const EXIT: Self = Self(1 << 2);
+    /// A `close` message is printed when a span is closed.
+    pub const CLOSE: Self = Self(1 << 3);
+    /// A `follows` message is printed when a span is marked as following from another span.
+    pub const FOLLOWS: Self = Self(1 << 4);
+    /// All span lifecycle messages are printed.
+    pub const ALL: Self =
+        Self(Self::NEW.0 | Self::ENTER.0 | Self::EXIT.0 | Self::CLOSE.0 | Self::FOLLOWS.0);
+
+    /// Returns `true` if all the span lifecycle messages in `other` are contained in `self`.
+    #[must_use]
+    pub fn contains(self, other: Self) -> bool {
+        self.0 & other.0 == other.0
+    }
+}
+
+impl BitOr for SpanEvents {
+    type Output = Self;
+
+    fn bitor(self, rhs: Self) -> Self::Output {
+        Self(self.0 | rhs.0)
+    }
+}
+
+/// The style in which the spans in the scope of each line are displayed.
+///
+/// Set with [`Builder::with_scope_style`].
+///
+/// [`Builder::with_scope_style`]: crate::Builder::with_scope_style
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum ScopeStyle {
+    /// Each span is displayed with its name, id, and fields, e.g.
+    /// `runtime.spawn[1]{kind=task, task.id=18}`.
+    ///
+    /// Spans which follow from other spans are followed by the names and ids of those spans,
+    /// e.g. `runtime.resource.async_op[3]{source="Sleep::new_timeout"} <- runtime.spawn[1]`.
+    #[default]
+    Full,
+    /// Each span is displayed with only its name and id, e.g. `runtime.spawn[1]`, followed by
+    /// the names and ids of the spans that it follows from.
+    Compact,
+}
+
+/// How the spans and events in the output are colored.
+///
+/// Set with [`Builder::with_palette`].
+///
+/// [`Builder::with_palette`]: crate::Builder::with_palette
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Palette {
+    /// Spans and events are colored by their kind, e.g. all tasks are green and all resources
+    /// are red.
+    #[default]
+    Kind,
+    /// Each task is given its own color, derived from its span id. The spans created within a
+    /// task, and the events within its scope, are colored with a lighter tint of the task's
+    /// color. Spans and events outside any task, as well as warnings, are still colored by
+    /// their kind.
+    Task,
+}
+
+/// The format in which each line of output is written.
+///
+/// Set with [`Builder::with_format`].
+///
+/// [`Builder::with_format`]: crate::Builder::with_format
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Format {
+    /// Human readable text, colorized with ANSI escape codes unless they are disabled.
+    #[default]
+    Text,
+    /// [JSON Lines], one JSON object per line.
+    ///
+    /// Each object contains the `timestamp`, `level`, `target`, and `kind` of the line, the
+    /// spans in its `scope` (each with `name`, `id`, `kind`, and `fields`), and its `fields`.
+    /// Span lifecycle lines additionally contain the `span_kind` and lifecycle `message` (`new`,
+    /// `enter`, `exit`, or `close`). The `exit` of an async op poll additionally contains the
+    /// `readiness` (`ready` or `pending`) of the poll.
+    ///
+    /// [JSON Lines]: https://jsonlines.org/
+    Json,
+    /// The [Chrome Trace Event Format], which can be opened in [Perfetto] or `chrome://tracing`.
+    ///
+    /// Instead of a line per span lifecycle message or event, a trace event is written for each
+    /// poll of a task and for the resources and async ops entered while polling. Each Tokio task
+    /// is shown as its own track, identified by the id of its span. Spans and events outside any
+    /// task are shown on a track for the thread they occur on. Wakes are shown as flow arrows
+    /// from the waking task to the next poll of the woken task.
+    ///
+    /// The output is a JSON array which is never closed, as allowed by the format, so it can be
+    /// written directly to a file. The opening of the array is written as soon as the layer is
+    /// built.
+    ///
+    /// [Chrome Trace Event Format]: https://docs.google.com/document/d/1CvAClvFfyA5R-PhYUmn5OOQtYMH4h6I0nSsKchNAySU
+    /// [Perfetto]: https://ui.perfetto.dev/
+    ChromeTrace,
+}
+
+/// The formatting options shared by all spans and events written by a layer.
+#[derive(Clone)]
+pub(crate) struct FmtConfig {
+    pub(crate) ansi: bool,
+    pub(crate) timestamp: Timestamp,
+    pub(crate) scope_style: ScopeStyle,
+    pub(crate) palette: Palette,
+    pub(crate) start: DateTime<Utc>,
+    /// Set in deterministic mode, see [`Builder::with_deterministic`].
+    ///
+    /// [`Builder::with_deterministic`]: crate::Builder::with_deterministic
+    pub(crate) deterministic: Option<Arc<Deterministic>>,
+}
+
+impl FmtConfig {
+    /// Returns the id of a span as it is displayed, which is a dense id in deterministic mode.
+    pub(crate) fn span_id(&self, id: &span::Id) -> u64 {
+        match &self.deterministic {
+            Some(deterministic) => deterministic.span_id(id),
+            None => id.into_u64(),
+        }
+    }
+}
 
 enum FmtFieldsKind {
     Span,
     Event(Option<String>),
 }
 
-#[derive(Clone)]
-enum SpanKind {
+/// The kind of a span, as classified from the spans in Tokio's instrumentation.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum SpanKind {
+    /// A span which isn't part of Tokio's instrumentation.
     Unknown,
+    /// A task, from a `runtime.spawn` span.
     Spawn,
+    /// A resource, such as a `Sleep` or a `Semaphore`, from a `runtime.resource` span.
     Resource,
+    /// An async operation on a resource, from a `runtime.resource.async_op` span.
     AsyncOp,
+    /// The polls of an async operation, from a `runtime.resource.async_op.poll` span.
     AsyncOpPoll,
 }
 
 impl SpanKind {
+    /// Classifies a span from the name and target in its metadata.
+    pub(crate) fn from_metadata(meta: &Metadata<'_>) -> Self {
+        match (meta.name(), meta.target()) {
+            ("runtime.spawn", _) | ("task", "tokio::task") => Self::Spawn,
+            ("runtime.resource", _) => Self::Resource,
+            ("runtime.resource.async_op", _) => Self::AsyncOp,
+            ("runtime.resource.async_op.poll", _) => Self::AsyncOpPoll,
+            _ => Self::Unknown,
+        }
+    }
+
     fn get_colors(&self) -> (Color, Color) {
         match self {
             Self::Spawn => (GREEN, GREEN_BOLD),
@@ -32,143 +228,488 @@ impl SpanKind {
             Self::Unknown => (Color::White, Color::White),
         }
     }
+
+    fn name(&self) -> &'static str {
+        match self {
+            Self::Spawn => "spawn",
+            Self::Resource => "resource",
+            Self::AsyncOp => "async_op",
+            Self::AsyncOpPoll => "async_op_poll",
+            Self::Unknown => "unknown",
+        }
+    }
 }
 
 pub(crate) struct FmtSpan {
-    id: span::Id,
+    /// The id which is displayed, see [`FmtConfig::span_id`].
+    display_id: u64,
     kind: SpanKind,
     name: String,
     fields: FmtFields,
+    /// The names and displayed ids of the spans that this span follows from.
+    follows: Vec<(String, u64)>,
+    /// Poll statistics, only present for task spans.
+    task_stats: Option<TaskStats>,
+    /// The current state, only present for resource spans.
+    state: Option<ResourceState>,
+    /// Poll statistics, only present for async op spans.
+    async_op_stats: Option<AsyncOpStats>,
+    /// The result of the current poll, only set for async op poll spans.
+    readiness: Option<Readiness>,
+    /// The regular and bold colors of the span, which depend on the [`Palette`].
+    colors: (Color, Color),
     formatted: String,
 }
 
 impl FmtSpan {
-    pub(crate) fn new(id: &span::Id, attrs: &span::Attributes<'_>, fields: FmtFields) -> Self {
+    /// Creates a new span, `task` is the closest task span in the scope it was created in,
+    /// which is only needed for [`Palette::Task`].
+    pub(crate) fn new(
+        now: DateTime<Utc>,
+        id: &span::Id,
+        attrs: &span::Attributes<'_>,
+        fields: FmtFields,
+        task: Option<&FmtSpan>,
+        config: &FmtConfig,
+    ) -> Self {
         let meta = attrs.metadata();
-        let kind = match (meta.name(), meta.target()) {
-            ("runtime.spawn", _) | ("task", "tokio::task") => SpanKind::Spawn,
-            ("runtime.resource", _) => SpanKind::Resource,
-            ("runtime.resource.async_op", _) => SpanKind::AsyncOp,
-            ("runtime.resource.async_op.poll", _) => SpanKind::AsyncOpPoll,
-            _ => SpanKind::Unknown,
-        };
+        let kind = SpanKind::from_metadata(meta);
 
+        let task_stats = matches!(kind, SpanKind::Spawn).then(|| TaskStats::new(now));
+        let state = matches!(kind, SpanKind::Resource).then(ResourceState::default);
+        let async_op_stats = matches!(kind, SpanKind::AsyncOp).then(AsyncOpStats::default);
+        let display_id = match &config.deterministic {
+            Some(deterministic) => deterministic.new_span_id(id),
+            None => id.into_u64(),
+        };
+        let colors = match (config.palette, &kind) {
+            (Palette::Task, SpanKind::Spawn) => task_colors(display_id),
+            (Palette::Task, _) => task.map_or_else(|| kind.get_colors(), |task| tint(task.colors)),
+            (Palette::Kind, _) => kind.get_colors(),
+        };
         let mut span = Self {
-            id: id.clone(),
+            display_id,
             kind,
             name: meta.name().to_owned(),
             fields,
+            follows: Vec::new(),
+            task_stats,
+            state,
+            async_op_stats,
+            readiness: None,
+            colors,
             formatted: String::new(),
         };
-        span.format();
+        span.relative_paths(config);
+        span.format(config);
         span
     }
 
-    pub(crate) fn format(&mut self) {
-        let (color, bold) = self.kind.get_colors();
+    pub(crate) fn format(&mut self, config: &FmtConfig) {
+        let (color, bold) = self.colors;
 
-        self.formatted = format!(
-            "{name}[{id}]{{{fields}}}",
-            name = &self.name,
-            id = self.id.into_u64().to_string().color(bold).bold(),
-            fields = self.fields.formatted(),
-        )
-        .color(color)
-        .to_string();
+        let id = self
+            .display_id
+            .to_string()
+            .color(bold)
+            .bold()
+            .ansi(config.ansi);
+        let mut formatted = match config.scope_style {
+            ScopeStyle::Full => format!(
+                "{name}[{id}]{{{fields}}}",
+                name = &self.name,
+                fields = self.fields.formatted_updated(),
+            ),
+            ScopeStyle::Compact => format!("{name}[{id}]", name = &self.name),
+        };
+        if let Some(state) = self.state.as_ref().and_then(ResourceState::formatted) {
+            let concrete_type = self.concrete_type().unwrap_or("resource");
+            formatted.push_str(&format!(" {concrete_type}{state}"));
+        }
+        for (name, id) in &self.follows {
+            formatted.push_str(&format!(
+                " <- {name}[{id}]",
+                id = id.to_string().color(bold).bold().ansi(config.ansi)
+            ));
+        }
+        self.formatted = formatted.color(color).ansi(config.ansi).to_string();
     }
 
     pub(crate) fn formatted(&self) -> &str {
         &self.formatted
     }
+
+    /// Records that this span follows from another span and updates the formatted span.
+    pub(crate) fn follows_from(&mut self, name: String, id: &span::Id, config: &FmtConfig) {
+        self.follows.push((name, config.span_id(id)));
+        self.format(config);
+    }
+
+    /// Returns the id of the span as it is displayed, see [`FmtConfig::span_id`].
+    pub(crate) fn display_id(&self) -> u64 {
+        self.display_id
+    }
+
+    /// Returns the name of the span, for display alongside the id.
+    pub(crate) fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Records new values for the span's fields and updates the formatted span.
+    pub(crate) fn record(&mut self, values: &span::Record<'_>, config: &FmtConfig) {
+        values.record(&mut self.fields);
+        self.relative_paths(config);
+        self.format(config);
+    }
+
+    /// Strips absolute paths from the `loc.file` field in deterministic mode.
+    fn relative_paths(&mut self, config: &FmtConfig) {
+        let Some(deterministic) = &config.deterministic else {
+            return;
+        };
+        if let Some(file) = self.fields.get("loc.file").and_then(FieldValue::as_str) {
+            if let Some(relative) = deterministic.relative_path(file) {
+                self.fields.replace("loc.file", FieldValue::Str(relative));
+            }
+        }
+    }
+
+    pub(crate) fn kind(&self) -> &SpanKind {
+        &self.kind
+    }
+
+    pub(crate) fn task_stats(&self) -> Option<&TaskStats> {
+        self.task_stats.as_ref()
+    }
+
+    pub(crate) fn task_stats_mut(&mut self) -> Option<&mut TaskStats> {
+        self.task_stats.as_mut()
+    }
+
+    pub(crate) fn async_op_stats(&self) -> Option<&AsyncOpStats> {
+        self.async_op_stats.as_ref()
+    }
+
+    pub(crate) fn async_op_stats_mut(&mut self) -> Option<&mut AsyncOpStats> {
+        self.async_op_stats.as_mut()
+    }
+
+    /// Returns the result of the current poll, if a `poll_op` event has been recorded for it.
+    pub(crate) fn readiness(&self) -> Option<Readiness> {
+        self.readiness
+    }
+
+    /// Sets the result of the current poll of an async op poll span.
+    pub(crate) fn set_readiness(&mut self, readiness: Option<Readiness>) {
+        self.readiness = readiness;
+    }
+
+    /// Applies a resource state update event to the state of a resource span and updates the
+    /// formatted span.
+    ///
+    /// Returns a description of the state transitions, or `None` if this isn't a resource span.
+    pub(crate) fn update_state(
+        &mut self,
+        fields: &FmtFields,
+        config: &FmtConfig,
+    ) -> Option<String> {
+        let description = self.state.as_mut()?.update(fields, config);
+        self.format(config);
+        Some(description)
+    }
+
+    /// Returns a short description of a resource span, its concrete type and id, e.g.
+    /// `Sleep[274877906945]`.
+    pub(crate) fn resource_name(&self) -> String {
+        let concrete_type = self.concrete_type().unwrap_or(&self.name);
+        format!("{concrete_type}[{id}]", id = self.display_id)
+    }
+
+    pub(crate) fn field(&self, name: &str) -> Option<&FieldValue> {
+        self.fields.get(name)
+    }
+
+    /// Returns the concrete type of a resource span, e.g. `Sleep`, from its `concrete_type`
+    /// field.
+    pub(crate) fn concrete_type(&self) -> Option<&str> {
+        self.fields
+            .get("concrete_type")
+            .and_then(FieldValue::as_str)
+    }
+
+    /// Returns the location the span was created at, from its `loc.file` and `loc.line` fields.
+    pub(crate) fn location(&self) -> Option<String> {
+        let file = self.fields.get("loc.file")?.as_str()?;
+        Some(match self.fields.get("loc.line") {
+            Some(line) => format!("{file}:{line}"),
+            None => file.to_owned(),
+        })
+    }
 }
 
 pub(crate) struct FmtEvent<'a> {
     timestamp: DateTime<Utc>,
     kind: EventKind,
+    level: tracing::Level,
     meta: &'a Metadata<'a>,
-    scope: &'a str,
     fields: FmtFields,
+    /// A formatted description which is displayed instead of the fields.
+    description: Option<String>,
+    /// The result of the poll which a span lifecycle message ends.
+    readiness: Option<Readiness>,
+    /// The waker operation and the id of the woken task's span, for waker events.
+    waker: Option<(WakerOp, span::Id)>,
+    /// The regular and bold colors, if they aren't those of the event's kind.
+    colors: Option<(Color, Color)>,
 }
 
 impl<'a> FmtEvent<'a> {
-    pub(crate) fn new(
-        timestamp: DateTime<Utc>,
-        meta: &'a Metadata<'a>,
-        scope: &'a str,
-        fields: FmtFields,
-    ) -> Self {
-        let kind = match meta.target() {
-            "runtime::waker" | "tokio::task::waker" => EventKind::Waker,
-            "runtime::resource::poll_op" => EventKind::PollOp,
-            "runtime::resource::state_update" => EventKind::ResourceStateUpdate,
-            "runtime::resource::async_op::state_update" => EventKind::AsyncOpUpdate,
-            _ => EventKind::Unknown,
-        };
+    pub(crate) fn new(timestamp: DateTime<Utc>, meta: &'a Metadata<'a>, fields: FmtFields) -> Self {
+        let kind = EventKind::from_metadata(meta);
 
-        Self {
+        let mut fmt_event = Self {
             timestamp,
             kind,
+            level: *meta.level(),
             meta,
-            scope,
             fields,
-        }
+            description: None,
+            readiness: None,
+            waker: None,
+            colors: None,
+        };
+        fmt_event.waker = fmt_event.parse_waker_op();
+        fmt_event
     }
 
     pub(crate) fn new_span_event(
         timestamp: DateTime<Utc>,
         span: &FmtSpan,
         meta: &'a Metadata<'a>,
-        scope: &'a str,
         message: String,
     ) -> Self {
         Self {
             timestamp,
             kind: EventKind::SpanEvent(span.kind.clone()),
+            level: *meta.level(),
             meta,
-            scope,
             fields: FmtFields::new_message(message),
+            description: None,
+            readiness: None,
+            waker: None,
+            colors: Some(span.colors),
         }
     }
 
-    pub(crate) fn formatted(&mut self) -> String {
-        let (color, bold) = self.kind.get_colors();
+    /// Creates a warning about a span, which is written at `WARN` level with a highlighted
+    /// `label` followed by the `message`.
+    pub(crate) fn new_warning(
+        timestamp: DateTime<Utc>,
+        meta: &'a Metadata<'a>,
+        label: &'static str,
+        message: String,
+    ) -> Self {
+        Self {
+            timestamp,
+            kind: EventKind::Warning(label),
+            level: tracing::Level::WARN,
+            meta,
+            fields: FmtFields::new_message(message),
+            description: None,
+            readiness: None,
+            waker: None,
+            colors: None,
+        }
+    }
 
-        let timestamp_format = format!(
-            "{date}T{time}.{subsec}",
-            date = "%Y-%m-%d".white().bold(),
-            time = "%H:%M:%S".white().bold(),
-            subsec = "%6fZ"
-        );
-        let timestamp = self
-            .timestamp
-            .format(&timestamp_format)
-            .to_string()
-            .dimmed();
-        if matches!(&self.kind, EventKind::SpanEvent(_)) {
+    pub(crate) fn timestamp(&self) -> DateTime<Utc> {
+        self.timestamp
+    }
+
+    pub(crate) fn kind(&self) -> &EventKind {
+        &self.kind
+    }
+
+    pub(crate) fn level(&self) -> tracing::Level {
+        self.level
+    }
+
+    /// Annotates a span lifecycle message with the result of the poll it ends.
+    pub(crate) fn annotate_readiness(&mut self, readiness: Readiness) {
+        self.readiness = Some(readiness);
+    }
+
+    /// Returns the readiness recorded in a `poll_op` event, if this is one.
+    pub(crate) fn poll_op_readiness(&self) -> Option<bool> {
+        if !matches!(self.kind, EventKind::PollOp) {
+            return None;
+        }
+        match self.fields.get("is_ready")? {
+            FieldValue::Bool(is_ready) => Some(*is_ready),
+            _ => None,
+        }
+    }
+
+    /// Returns `true` if this is a resource state update event.
+    pub(crate) fn is_state_update(&self) -> bool {
+        matches!(self.kind, EventKind::ResourceStateUpdate)
+    }
+
+    pub(crate) fn fields(&self) -> &FmtFields {
+        &self.fields
+    }
+
+    /// Sets a formatted description to display instead of the event's fields.
+    pub(crate) fn describe(&mut self, description: String) {
+        self.description = Some(description);
+    }
+
+    /// Returns the waker operation and the id of the woken task's span, if this is a waker
+    /// event.
+    pub(crate) fn waker_op(&self) -> Option<(WakerOp, span::Id)> {
+        self.waker.clone()
+    }
+
+    /// Replaces the span id in the `task.id` field of a waker event with the id which is
+    /// displayed for the task's span, see [`FmtConfig::span_id`].
+    pub(crate) fn display_task_id(&mut self, config: &FmtConfig) {
+        if config.deterministic.is_none() {
+            return;
+        }
+        if let Some((_, task_id)) = &self.waker {
+            let display_id = config.span_id(task_id);
+            self.fields.replace("task.id", FieldValue::U64(display_id));
+        }
+    }
+
+    fn parse_waker_op(&self) -> Option<(WakerOp, span::Id)> {
+        if !matches!(self.kind, EventKind::Waker) {
+            return None;
+        }
+        let op = match self.fields.get("op")?.as_str()? {
+            "waker.clone" => WakerOp::Clone,
+            "waker.drop" => WakerOp::Drop,
+            "waker.wake" => WakerOp::Wake,
+            "waker.wake_by_ref" => WakerOp::WakeByRef,
+            _ => return None,
+        };
+        // The `task.id` field holds the id of the task's span, not the Tokio task id.
+        let task_span_id = self.fields.get("task.id")?.as_u64()?;
+        (task_span_id != 0).then(|| (op, span::Id::from_u64(task_span_id)))
+    }
+
+    pub(crate) fn formatted(
+        &mut self,
+        config: &FmtConfig,
+        scope: &str,
+        emphasis: Emphasis,
+    ) -> String {
+        let line = self.format_line(config, scope);
+        match emphasis {
+            Emphasis::Full => line,
+            // The scope and descriptions are already formatted, so their colors are stripped
+            // rather than left out.
+            Emphasis::Dimmed if config.ansi => strip_ansi(&line).color(GREY).ansi(true).to_string(),
+            Emphasis::Dimmed => line,
+        }
+    }
+
+    /// Colors the event with a tint of the color of `task`, the closest task in its scope.
+    ///
+    /// Span lifecycle messages keep the color of their span, and warnings keep their own color.
+    pub(crate) fn inherit_colors(&mut self, task: &FmtSpan) {
+        if matches!(self.kind, EventKind::SpanEvent(_) | EventKind::Warning(_)) {
+            return;
+        }
+        self.colors = Some(tint(task.colors));
+    }
+
+    fn format_line(&mut self, config: &FmtConfig, scope: &str) -> String {
+        let (color, bold) = self.colors.unwrap_or_else(|| self.kind.get_colors());
+        let ansi = config.ansi;
+
+        let timestamp = self.format_timestamp(config);
+        if let EventKind::Warning(label) = &self.kind {
             format!(
-                "{timestamp} {level:>5} {scope}{formatted}",
-                level = format_level(*self.meta.level()),
-                scope = self.scope,
+                "{timestamp}{level:>5} {scope}{label}: {formatted}",
+                level = format_level(self.level, ansi),
+                label = label.color(bold).bold().underline().ansi(ansi),
+                formatted = self.fields.formatted_updated().color(color).ansi(ansi)
+            )
+        } else if matches!(&self.kind, EventKind::SpanEvent(_)) {
+            let mut line = format!(
+                "{timestamp}{level:>5} {scope}{formatted}",
+                level = format_level(self.level, ansi),
+                scope = scope,
                 formatted = self
                     .fields
                     .formatted_updated()
                     .color(bold)
                     .underline()
                     .bold()
-            )
+                    .ansi(ansi)
+            );
+            if let Some(readiness) = self.readiness {
+                line.push(' ');
+                line.push_str(&readiness.formatted(ansi));
+            }
+            line
         } else {
             format!(
-                "{timestamp} {level:>5} {scope}{target}: {formatted}",
-                level = format_level(*self.meta.level()),
-                scope = self.scope,
-                target = self.meta.target().color(bold).bold(),
-                formatted = self.fields.formatted_updated().color(color)
+                "{timestamp}{level:>5} {scope}{target}: {formatted}",
+                level = format_level(self.level, ansi),
+                scope = scope,
+                target = self.meta.target().color(bold).bold().ansi(ansi),
+                formatted = match &self.description {
+                    Some(description) => description.clone(),
+                    None => self
+                        .fields
+                        .formatted_updated()
+                        .color(color)
+                        .ansi(ansi)
+                        .to_string(),
+                },
             )
         }
     }
+
+    /// Formats the timestamp, including the separating space if there is a timestamp.
+    fn format_timestamp(&self, config: &FmtConfig) -> String {
+        let ansi = config.ansi;
+        let timestamp_format = match config.timestamp {
+            Timestamp::DateTime => format!(
+                "{date}T{time}.{subsec}",
+                date = "%Y-%m-%d".white().bold().ansi(ansi),
+                time = "%H:%M:%S".white().bold().ansi(ansi),
+                subsec = "%6fZ"
+            ),
+            Timestamp::Time => format!(
+                "{time}.{subsec}",
+                time = "%H:%M:%S".white().bold().ansi(ansi),
+                subsec = "%6f"
+            ),
+            Timestamp::Uptime => {
+                let uptime = (self.timestamp - config.start).to_std().unwrap_or_default();
+                let uptime = format!(
+                    "{secs:>4}.{micros:06}s",
+                    secs = uptime.as_secs(),
+                    micros = uptime.subsec_micros()
+                );
+                return format!("{} ", uptime.dimmed().ansi(ansi));
+            }
+            Timestamp::None => return String::new(),
+        };
+        let timestamp = self
+            .timestamp
+            .format(&timestamp_format)
+            .to_string()
+            .dimmed()
+            .ansi(ansi);
+        format!("{timestamp} ")
+    }
 }
 
-fn format_level(level: tracing::Level) -> String {
+fn format_level(level: tracing::Level, ansi: bool) -> String {
     match level {
         tracing::Level::TRACE => "TRACE".color(PURPLE),
         tracing::Level::DEBUG => "DEBUG".color(BLUE),
@@ -176,19 +717,41 @@ fn format_level(level: tracing::Level) -> String {
         tracing::Level::WARN => " WARN".color(YELLOW),
         tracing::Level::ERROR => "ERROR".color(RED),
     }
+    .ansi(ansi)
     .to_string()
 }
 
-enum EventKind {
+/// The kind of an event, as classified from the events in Tokio's instrumentation.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum EventKind {
+    /// An event which isn't part of Tokio's instrumentation.
     Unknown,
+    /// An operation on a task's waker, such as a wake.
     Waker,
+    /// The result of polling an operation on a resource.
     PollOp,
+    /// An update to the state of a resource.
     ResourceStateUpdate,
+    /// An update to the state of an async operation.
     AsyncOpUpdate,
+    /// A span lifecycle message (`new`, `enter`, `exit`, or `close`) generated by ari.
     SpanEvent(SpanKind),
+    /// A warning generated by ari itself, with a short label describing it.
+    Warning(&'static str),
 }
 
 impl EventKind {
+    /// Classifies an event from the target in its metadata.
+    pub(crate) fn from_metadata(meta: &Metadata<'_>) -> Self {
+        match meta.target() {
+            "runtime::waker" | "tokio::task::waker" => Self::Waker,
+            "runtime::resource::poll_op" => Self::PollOp,
+            "runtime::resource::state_update" => Self::ResourceStateUpdate,
+            "runtime::resource::async_op::state_update" => Self::AsyncOpUpdate,
+            _ => Self::Unknown,
+        }
+    }
+
     fn get_colors(&self) -> (Color, Color) {
         match self {
             Self::Waker => (PURPLE, PURPLE_BOLD),
@@ -197,13 +760,217 @@ impl EventKind {
             Self::AsyncOpUpdate => (TURQUOISE, TURQUOISE_BOLD),
             Self::Unknown => (Color::White, Color::White),
             Self::SpanEvent(span_kind) => span_kind.get_colors(),
+            Self::Warning(_) => (AMBER, AMBER_BOLD),
+        }
+    }
+
+    pub(crate) fn name(&self) -> &'static str {
+        match self {
+            Self::Waker => "waker",
+            Self::PollOp => "poll_op",
+            Self::ResourceStateUpdate => "resource_state_update",
+            Self::AsyncOpUpdate => "async_op_update",
+            Self::Unknown => "event",
+            Self::SpanEvent(_) => "span",
+            Self::Warning(_) => "warning",
+        }
+    }
+}
+
+/// How prominently a line is displayed, which depends on whether its scope is in the focus.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum Emphasis {
+    /// The line is displayed in full color.
+    Full,
+    /// The line is displayed in grey, so that the lines in the focus stand out.
+    Dimmed,
+}
+
+/// The result of a poll of an async op, recorded by Tokio in `poll_op` events.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum Readiness {
+    /// The op was ready after the given number of `Pending` polls.
+    Ready {
+        pending_polls: u64,
+    },
+    Pending,
+}
+
+impl Readiness {
+    pub(crate) fn name(self) -> &'static str {
+        match self {
+            Self::Ready { .. } => "ready",
+            Self::Pending => "pending",
+        }
+    }
+
+    /// Formats the readiness as an annotation on the end of a poll, e.g. `-> Pending` or
+    /// `-> Ready (after 2 Pending)`.
+    fn formatted(self, ansi: bool) -> String {
+        match self {
+            Self::Ready { pending_polls: 0 } => format!(
+                "{arrow} {ready}",
+                arrow = "->".color(GREEN).ansi(ansi),
+                ready = "Ready".color(GREEN_BOLD).bold().ansi(ansi),
+            ),
+            Self::Ready { pending_polls } => format!(
+                "{arrow} {ready} {after}",
+                arrow = "->".color(GREEN).ansi(ansi),
+                ready = "Ready".color(GREEN_BOLD).bold().ansi(ansi),
+                after = format!("(after {pending_polls} Pending)")
+                    .color(GREEN)
+                    .ansi(ansi),
+            ),
+            Self::Pending => format!(
+                "{arrow} {pending}",
+                arrow = "->".color(ORANGE).ansi(ansi),
+                pending = "Pending".color(ORANGE_BOLD).bold().ansi(ansi),
+            ),
+        }
+    }
+}
+
+/// Returns `true` if a location (`file:line`) matches a pattern, which is either a whole
+/// location or just the file.
+pub(crate) fn location_matches(location: &str, pattern: &str) -> bool {
+    location
+        .strip_prefix(pattern)
+        .map_or(false, |rest| rest.is_empty() || rest.starts_with(':'))
+}
+
+/// An operation on a task's waker, recorded by Tokio in waker events.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum WakerOp {
+    Clone,
+    Drop,
+    Wake,
+    WakeByRef,
+}
+
+impl WakerOp {
+    fn name(self) -> &'static str {
+        match self {
+            Self::Clone => "clone",
+            Self::Drop => "drop",
+            Self::Wake => "wake",
+            Self::WakeByRef => "wake_by_ref",
+        }
+    }
+
+    /// Returns `true` if the operation wakes the task.
+    pub(crate) fn is_wake(self) -> bool {
+        matches!(self, Self::Wake | Self::WakeByRef)
+    }
+}
+
+/// Formats a waker operation, attributing it to the task which is woken and the task which
+/// performed the operation (`source`), if any.
+///
+/// The task whose waker it is is displayed in its own colors, e.g.
+/// `wake_by_ref task[7] (spawned at src/foo.rs:42) from task[3]`.
+pub(crate) fn format_waker_op(
+    op: WakerOp,
+    task_id: &span::Id,
+    task: Option<&FmtSpan>,
+    source: Option<&span::Id>,
+    config: &FmtConfig,
+) -> String {
+    let ansi = config.ansi;
+    let (event_color, event_bold) = EventKind::Waker.get_colors();
+    // The task's own colors, which follow the palette.
+    let (color, bold) = task.map_or((Color::White, Color::White), |task| task.colors);
+
+    let mut target = format!(
+        "task[{id}]",
+        id = config
+            .span_id(task_id)
+            .to_string()
+            .color(bold)
+            .bold()
+            .ansi(ansi)
+    );
+    if let Some(location) = task.and_then(FmtSpan::location) {
+        target.push_str(&format!(" (spawned at {location})"));
+    }
+
+    let source = match (source, op.is_wake()) {
+        (Some(source), true) if source == task_id => "self-wake".to_owned(),
+        (Some(source), false) if source == task_id => "by itself".to_owned(),
+        (Some(source), is_wake) => format!(
+            "{preposition} task[{id}]",
+            preposition = if is_wake { "from" } else { "by" },
+            id = config
+                .span_id(source)
+                .to_string()
+                .color(event_bold)
+                .bold()
+                .ansi(ansi)
+        ),
+        (None, true) => "from outside any task".to_owned(),
+        (None, false) => "outside any task".to_owned(),
+    };
+
+    format!(
+        "{op} {target} {source}",
+        op = op.name().color(event_bold).bold().ansi(ansi),
+        target = target.color(color).ansi(ansi),
+        source = source.color(event_color).ansi(ansi),
+    )
+}
+
+/// A recorded field value, which keeps its type for structured output.
+#[derive(Clone)]
+pub(crate) enum FieldValue {
+    Bool(bool),
+    I64(i64),
+    U64(u64),
+    F64(f64),
+    Str(String),
+    Debug(String),
+}
+
+impl FieldValue {
+    pub(crate) fn as_str(&self) -> Option<&str> {
+        match self {
+            Self::Str(value) | Self::Debug(value) => Some(value),
+            _ => None,
+        }
+    }
+
+    pub(crate) fn as_u64(&self) -> Option<u64> {
+        match self {
+            Self::U64(value) => Some(*value),
+            Self::I64(value) => u64::try_from(*value).ok(),
+            _ => None,
+        }
+    }
+
+    fn as_i128(&self) -> Option<i128> {
+        match self {
+            Self::U64(value) => Some((*value).into()),
+            Self::I64(value) => Some((*value).into()),
+            _ => None,
+        }
+    }
+}
+
+impl std::fmt::Display for FieldValue {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        // Values are displayed as they would be if they were recorded as `Debug`.
+        match self {
+            Self::Bool(value) => write!(f, "{value:?}"),
+            Self::I64(value) => write!(f, "{value:?}"),
+            Self::U64(value) => write!(f, "{value:?}"),
+            Self::F64(value) => write!(f, "{value:?}"),
+            Self::Str(value) => write!(f, "{value:?}"),
+            Self::Debug(value) => f.write_str(value),
         }
     }
 }
 
 pub(crate) struct FmtFields {
     kind: FmtFieldsKind,
-    fields: Vec<(String, String)>,
+    fields: Vec<(String, FieldValue)>,
     dirty: bool,
     formatted: String,
 }
@@ -238,10 +1005,24 @@ impl FmtFields {
         fields
     }
 
+    pub(crate) fn get(&self, name: &str) -> Option<&FieldValue> {
+        self.fields
+            .iter()
+            .find_map(|(field, value)| (field == name).then_some(value))
+    }
+
     pub(crate) fn formatted(&self) -> &str {
         &self.formatted
     }
 
+    /// Replaces the value of an existing field.
+    fn replace(&mut self, name: &str, value: FieldValue) {
+        if let Some((_, existing)) = self.fields.iter_mut().find(|(field, _)| field == name) {
+            *existing = value;
+            self.dirty = true;
+        }
+    }
+
     pub(crate) fn formatted_updated(&mut self) -> &str {
         if self.dirty {
             self.format();
@@ -257,7 +1038,7 @@ impl FmtFields {
             .filter_map(|(name, value)| {
                 if name == "message" {
                     if let FmtFieldsKind::Event(_) = self.kind {
-                        self.kind = FmtFieldsKind::Event(Some(value.clone()));
+                        self.kind = FmtFieldsKind::Event(Some(value.to_string()));
                         return None;
                     }
                 }
@@ -276,10 +1057,162 @@ impl FmtFields {
     }
 }
 
+impl FmtFields {
+    fn record(&mut self, field: &tracing::field::Field, value: FieldValue) {
+        let name = field.name();
+        // A span's fields may be recorded again after it was created, the new value replaces
+        // the old one.
+        let existing = match self.kind {
+            FmtFieldsKind::Span => self.fields.iter_mut().find(|(field, _)| field == name),
+            FmtFieldsKind::Event(_) => None,
+        };
+        match existing {
+            Some((_, existing)) => *existing = value,
+            None => self.fields.push((name.into(), value)),
+        }
+        self.dirty = true;
+    }
+}
+
 impl Visit for FmtFields {
+    fn record_f64(&mut self, field: &tracing::field::Field, value: f64) {
+        self.record(field, FieldValue::F64(value));
+    }
+
+    fn record_i64(&mut self, field: &tracing::field::Field, value: i64) {
+        self.record(field, FieldValue::I64(value));
+    }
+
+    fn record_u64(&mut self, field: &tracing::field::Field, value: u64) {
+        self.record(field, FieldValue::U64(value));
+    }
+
+    fn record_bool(&mut self, field: &tracing::field::Field, value: bool) {
+        self.record(field, FieldValue::Bool(value));
+    }
+
+    fn record_str(&mut self, field: &tracing::field::Field, value: &str) {
+        self.record(field, FieldValue::Str(value.to_owned()));
+    }
+
     fn record_debug(&mut self, field: &tracing::field::Field, value: &dyn std::fmt::Debug) {
-        self.fields
-            .push((field.name().into(), format!("{value:?}")));
-        self.dirty = true;
+        self.record(field, FieldValue::Debug(format!("{value:?}")));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use colored::Colorize;
+    use tracing_subscriber::prelude::*;
+
+    use super::{strip_ansi, GREY};
+    use crate::{
+        test_support::{lines_of, run, Output},
+        Focus, Layer, Timestamp,
+    };
+
+    /// Returns the line which ends with `suffix`.
+    fn line_ending_with<'l>(lines: &'l [String], suffix: &str) -> &'l str {
+        lines
+            .iter()
+            .find(|line| line.ends_with(suffix))
+            .unwrap_or_else(|| panic!("no line ends with `{suffix}` in {lines:#?}"))
+    }
+
+    #[test]
+    fn recorded_fields_are_shown_in_the_scope() {
+        let lines = lines_of(Layer::builder(), async {
+            let span = tracing::info_span!("request", status = tracing::field::Empty);
+            let _guard = span.enter();
+            tracing::info!("before");
+            span.record("status", 200);
+            tracing::info!("after");
+        });
+
+        // The span is rendered as `request[<id>]{<fields>}` in the scope of each line.
+        assert!(line_ending_with(&lines, ": before").contains("]{} "));
+        assert!(line_ending_with(&lines, ": after").contains("]{status=200} "));
+    }
+
+    #[test]
+    fn follows_from_is_shown_with_both_ids() {
+        let mut ids = None;
+        let lines = lines_of(Layer::builder(), async {
+            let cause = tracing::info_span!("cause");
+            let effect = tracing::info_span!("effect");
+            effect.follows_from(&cause);
+            effect.in_scope(|| tracing::info!("caused"));
+            ids = cause.id().zip(effect.id());
+        });
+        let (cause, effect) = ids.unwrap();
+        let (cause, effect) = (cause.into_u64(), effect.into_u64());
+
+        let follows = line_ending_with(&lines, &format!(" follows cause[{cause}]"));
+        assert!(follows.contains(&format!(" effect[{effect}]{{}} ")));
+        let caused = line_ending_with(&lines, ": caused");
+        assert!(caused.contains(&format!(" effect[{effect}]{{}} <- cause[{cause}] ")));
+    }
+
+    #[test]
+    fn async_op_polls_are_annotated_with_readiness() {
+        let lines = lines_of(Layer::builder(), async {
+            let (tx, rx) = tokio::sync::oneshot::channel();
+            let receiver = tokio::spawn(rx);
+            tokio::task::yield_now().await;
+            tx.send(()).unwrap();
+            receiver.await.unwrap().unwrap();
+        });
+        // Tokio's runtime has oneshot channels of its own, which are created elsewhere.
+        let lines = lines
+            .iter()
+            .filter(|line| line.contains("loc.file=\"src/fmt/mod.rs\""))
+            .collect::<Vec<_>>();
+        let poll_exits = lines
+            .iter()
+            .filter(|line| line.contains(" runtime.resource.async_op.poll["))
+            .filter_map(|line| line.split_once("{} exit").map(|(_, readiness)| readiness))
+            .collect::<Vec<_>>();
+
+        assert_eq!(poll_exits, [" -> Pending", " -> Ready (after 1 Pending)"]);
+        assert!(lines
+            .iter()
+            .any(|line| line.ends_with("} close pending_polls=1 ready=true")));
+    }
+
+    #[test]
+    fn lines_outside_the_focus_are_dimmed() {
+        let output = Output::default();
+        let layer = Layer::builder()
+            .with_writer(output.clone())
+            .with_ansi(true)
+            .with_timestamp(Timestamp::None)
+            .with_focus(Focus::task_name("a").with_dimming(true))
+            .build();
+        run(tracing_subscriber::registry().with(layer), async {
+            for name in ["a", "b"] {
+                tokio::task::Builder::new()
+                    .name(name)
+                    .spawn(async move { tracing::info!(name, "polled") })
+                    .unwrap()
+                    .await
+                    .unwrap();
+            }
+        });
+        let lines = output.lines();
+        let event_of = |name: &str| {
+            let suffix = format!(r#"name="{name}" polled"#);
+            lines
+                .iter()
+                .find(|line| strip_ansi(line).ends_with(&suffix))
+                .unwrap_or_else(|| panic!("no event from `{name}` in {lines:#?}"))
+        };
+
+        // The whole line is grey, without the colors of the scope or the event.
+        let dimmed = event_of("b");
+        assert_eq!(*dimmed, strip_ansi(dimmed).color(GREY).to_string());
+        assert_ne!(*dimmed, strip_ansi(dimmed));
+        let focused = event_of("a");
+        assert_ne!(*focused, strip_ansi(focused).color(GREY).to_string());
+        assert_ne!(*focused, strip_ansi(focused));
     }
 }
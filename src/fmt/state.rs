@@ -0,0 +1,213 @@
+use colored::Colorize;
+
+use super::{color::Ansi, EventKind, FieldValue, FmtConfig, FmtFields};
+
+/// The current state of a resource, built up from `runtime::resource::state_update` events.
+///
+/// Each attribute is stored in the order it was first updated, along with its unit, if any.
+#[derive(Default)]
+pub(crate) struct ResourceState {
+    attributes: Vec<StateAttribute>,
+}
+
+struct StateAttribute {
+    name: String,
+    value: FieldValue,
+    unit: Option<String>,
+}
+
+/// How the value in a state update is applied to the current value of an attribute.
+#[derive(Clone, Copy)]
+enum StateOp {
+    Override,
+    Add,
+    Sub,
+}
+
+impl ResourceState {
+    /// Applies the attribute updates in the fields of a state update event.
+    ///
+    /// Each attribute `<attr>` may be accompanied by an `<attr>.unit` and an `<attr>.op`, which
+    /// is one of `override`, `add`, or `sub`. Without an op, the value overrides the current one.
+    ///
+    /// Returns a description of the transitions, e.g. `permits: 3 -> 2`.
+    pub(crate) fn update(&mut self, fields: &FmtFields, config: &FmtConfig) -> String {
+        let ansi = config.ansi;
+        let (color, bold) = EventKind::ResourceStateUpdate.get_colors();
+
+        fields
+            .fields
+            .iter()
+            .filter(|(name, _)| !name.contains('.') && name != "message")
+            .map(|(name, value)| {
+                let op = match fields
+                    .get(&format!("{name}.op"))
+                    .and_then(FieldValue::as_str)
+                {
+                    Some("add") => StateOp::Add,
+                    Some("sub") => StateOp::Sub,
+                    _ => StateOp::Override,
+                };
+                let unit = fields
+                    .get(&format!("{name}.unit"))
+                    .and_then(FieldValue::as_str)
+                    .map(ToOwned::to_owned);
+                let (previous, current) = self.apply(name, value, op, unit);
+
+                let transition = match previous {
+                    Some(previous) => format!("{previous} -> {current}"),
+                    None => current,
+                };
+                format!(
+                    "{name}: {transition}",
+                    name = name.as_str().color(bold).bold().ansi(ansi),
+                    transition = transition.color(color).ansi(ansi),
+                )
+            })
+            .collect::<Vec<_>>()
+            .join(", ")
+    }
+
+    /// Applies a single update, returning the formatted previous value (if there was one) and
+    /// the formatted current value.
+    fn apply(
+        &mut self,
+        name: &str,
+        value: &FieldValue,
+        op: StateOp,
+        unit: Option<String>,
+    ) -> (Option<String>, String) {
+        let Some(attribute) = self.attributes.iter_mut().find(|attr| attr.name == name) else {
+            let attribute = StateAttribute {
+                name: name.to_owned(),
+                value: value.clone(),
+                unit,
+            };
+            let current = attribute.formatted_value();
+            self.attributes.push(attribute);
+            return (None, current);
+        };
+
+        let previous = attribute.formatted_value();
+        attribute.value = match op {
+            StateOp::Override => value.clone(),
+            StateOp::Add | StateOp::Sub => apply_op(&attribute.value, value, op),
+        };
+        if unit.is_some() {
+            attribute.unit = unit;
+        }
+        (Some(previous), attribute.formatted_value())
+    }
+
+    /// Formats the current state as it is displayed after a resource in the scope, e.g.
+    /// `{permits=2}`.
+    pub(crate) fn formatted(&self) -> Option<String> {
+        if self.attributes.is_empty() {
+            return None;
+        }
+        let attributes = self
+            .attributes
+            .iter()
+            .map(|attr| {
+                format!(
+                    "{name}={value}",
+                    name = attr.name,
+                    value = attr.formatted_value()
+                )
+            })
+            .collect::<Vec<_>>()
+            .join(", ");
+        Some(format!("{{{attributes}}}"))
+    }
+
+    /// Returns the current state as a JSON object.
+    pub(crate) fn json(&self) -> serde_json::Value {
+        serde_json::Value::Object(
+            self.attributes
+                .iter()
+                .map(|attr| (attr.name.clone(), attr.value.json()))
+                .collect(),
+        )
+    }
+}
+
+impl StateAttribute {
+    fn formatted_value(&self) -> String {
+        match &self.unit {
+            Some(unit) => format!("{value}{unit}", value = self.value),
+            None => self.value.to_string(),
+        }
+    }
+}
+
+/// Adds `delta` to or subtracts it from `current`.
+///
+/// Unsigned values which would go negative become signed. If either value isn't a number, the
+/// delta replaces the current value, as there is no sensible way to apply it.
+fn apply_op(current: &FieldValue, delta: &FieldValue, op: StateOp) -> FieldValue {
+    let sign = if matches!(op, StateOp::Sub) { -1 } else { 1 };
+    match (current, delta) {
+        (FieldValue::F64(current), FieldValue::F64(delta)) => {
+            FieldValue::F64(current + f64::from(sign) * delta)
+        }
+        _ => match (current.as_i128(), delta.as_i128()) {
+            (Some(current_int), Some(delta_int)) => {
+                let value = current_int + i128::from(sign) * delta_int;
+                match (current, u64::try_from(value), i64::try_from(value)) {
+                    (FieldValue::U64(_), Ok(value), _) => FieldValue::U64(value),
+                    (_, _, Ok(value)) => FieldValue::I64(value),
+                    // Out of range of both types, keep the previous value.
+                    _ => current.clone(),
+                }
+            }
+            _ => delta.clone(),
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{apply_op, StateOp};
+    use crate::{fmt::FieldValue, test_support::lines_of, Layer};
+
+    #[test]
+    fn state_updates_show_transitions_and_current_state() {
+        let lines = lines_of(Layer::builder(), async {
+            let semaphore = tokio::sync::Semaphore::new(2);
+            let _permit = semaphore.acquire().await.unwrap();
+        });
+        let update = |transition: &str| {
+            lines
+                .iter()
+                .find(|line| line.ends_with(&format!("state_update: {transition}")))
+                .unwrap_or_else(|| panic!("no `{transition}` update in {lines:#?}"))
+        };
+
+        assert!(update("permits: 2").contains(" Semaphore{permits=2} "));
+        assert!(update("permits: 2 -> 1").contains(" Semaphore{permits=1} "));
+        assert!(update("permits: 1 -> 2").contains(" Semaphore{permits=2} "));
+    }
+
+    #[test]
+    fn ops() {
+        let apply = |current, delta, op| apply_op(&current, &delta, op).to_string();
+        assert_eq!(
+            apply(FieldValue::U64(2), FieldValue::U64(3), StateOp::Add),
+            "5"
+        );
+        // Unsigned values which would go negative become signed.
+        assert_eq!(
+            apply(FieldValue::U64(2), FieldValue::U64(3), StateOp::Sub),
+            "-1"
+        );
+        assert_eq!(
+            apply(FieldValue::F64(1.5), FieldValue::F64(1.0), StateOp::Sub),
+            "0.5"
+        );
+        // A delta which isn't a number replaces the value.
+        assert_eq!(
+            apply(FieldValue::U64(2), FieldValue::Bool(true), StateOp::Add),
+            "true"
+        );
+    }
+}
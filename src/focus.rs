@@ -0,0 +1,192 @@
+//! Focus mode, which only writes the output related to a single task.
+//!
+//! See the documentation on [`Focus`] for more details.
+use std::sync::{PoisonError, RwLock, RwLockReadGuard};
+
+use crate::fmt::{location_matches, FieldValue, FmtSpan, SpanKind};
+
+/// Selects a single task to follow, leaving out the output of all the others.
+///
+/// When a focus is set, a line is only written if the task is in its scope, which includes
+/// the task's own lifecycle, the spans and events within it, and the warnings about it. Waker
+/// events which target the task are also written, wherever they come from. If several tasks
+/// match, the output of all of them is written.
+///
+/// Instead of leaving out the other lines, they can be dimmed with [`Focus::with_dimming`], so
+/// that the lines in the focus stand out while the surrounding output stays visible.
+///
+/// Set with [`Builder::with_focus`] and changed at runtime with [`Handle::set_focus`] and
+/// [`Handle::clear_focus`]. Focus mode applies to the text and JSON formats, a Chrome trace
+/// always contains every task.
+///
+/// [`Builder::with_focus`]: crate::Builder::with_focus
+/// [`Handle::set_focus`]: crate::Handle::set_focus
+/// [`Handle::clear_focus`]: crate::Handle::clear_focus
+///
+/// # Examples
+///
+/// ```rust
+/// use ari_subscriber::{Focus, Layer};
+///
+/// let layer = Layer::builder()
+///     .with_focus(Focus::task_name("connection"))
+///     .build();
+/// let handle = layer.handle();
+///
+/// // Later on, follow the task spawned on line 42 instead, keeping the other lines dimmed.
+/// handle.set_focus(Focus::spawn_location("src/server.rs:42").with_dimming(true));
+/// ```
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Focus {
+    selector: Selector,
+    dimming: bool,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+enum Selector {
+    SpanId(u64),
+    TaskId(u64),
+    TaskName(String),
+    SpawnLocation(String),
+}
+
+impl Focus {
+    /// Follows the task with the given span id, as it appears in the output, e.g. `3` for
+    /// `runtime.spawn[3]`.
+    #[must_use]
+    pub fn span_id(id: u64) -> Self {
+        Self::new(Selector::SpanId(id))
+    }
+
+    /// Follows the task with the given Tokio task id, from the task span's `task.id` field.
+    #[must_use]
+    pub fn task_id(id: u64) -> Self {
+        Self::new(Selector::TaskId(id))
+    }
+
+    /// Follows the task with the given name, from the task span's `task.name` field.
+    #[must_use]
+    pub fn task_name(name: impl Into<String>) -> Self {
+        Self::new(Selector::TaskName(name.into()))
+    }
+
+    /// Follows the tasks spawned at the given location, either `file:line` or just `file`, as
+    /// it appears in the task span's `loc.file` and `loc.line` fields.
+    #[must_use]
+    pub fn spawn_location(location: impl Into<String>) -> Self {
+        Self::new(Selector::SpawnLocation(location.into()))
+    }
+
+    fn new(selector: Selector) -> Self {
+        Self {
+            selector,
+            dimming: false,
+        }
+    }
+
+    /// Sets whether the lines outside the focus are written in grey, instead of being left out.
+    ///
+    /// Dimming only applies to the text format with ANSI escape codes enabled, otherwise every
+    /// line is written as usual. Defaults to `false`.
+    #[must_use]
+    pub fn with_dimming(mut self, dimming: bool) -> Self {
+        self.dimming = dimming;
+        self
+    }
+
+    /// Returns `true` if the lines outside the focus are dimmed instead of being left out.
+    pub(crate) fn dimming(&self) -> bool {
+        self.dimming
+    }
+
+    /// Returns `true` if the span is a task selected by this focus.
+    pub(crate) fn selects(&self, fmt_span: &FmtSpan) -> bool {
+        if !matches!(fmt_span.kind(), SpanKind::Spawn) {
+            return false;
+        }
+        match &self.selector {
+            Selector::SpanId(id) => fmt_span.display_id() == *id,
+            Selector::TaskId(id) => {
+                fmt_span.field("task.id").and_then(FieldValue::as_u64) == Some(*id)
+            }
+            Selector::TaskName(name) => {
+                fmt_span.field("task.name").and_then(FieldValue::as_str) == Some(name.as_str())
+            }
+            Selector::SpawnLocation(location) => fmt_span
+                .location()
+                .map_or(false, |spawned_at| location_matches(&spawned_at, location)),
+        }
+    }
+}
+
+/// The current focus, shared between a layer and its handles.
+#[derive(Debug)]
+pub(crate) struct SharedFocus {
+    focus: RwLock<Option<Focus>>,
+}
+
+impl SharedFocus {
+    pub(crate) fn new(focus: Option<Focus>) -> Self {
+        Self {
+            focus: RwLock::new(focus),
+        }
+    }
+
+    pub(crate) fn get(&self) -> RwLockReadGuard<'_, Option<Focus>> {
+        self.focus.read().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    pub(crate) fn set(&self, focus: Option<Focus>) {
+        *self.focus.write().unwrap_or_else(PoisonError::into_inner) = focus;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{test_support::lines_of, Focus, Layer};
+
+    /// Spawns the tasks `a` and `b` one after the other, each of which emits an event.
+    async fn spawn_a_and_b() {
+        for name in ["a", "b"] {
+            tokio::task::Builder::new()
+                .name(name)
+                .spawn(async move { tracing::info!(name, "polled") })
+                .unwrap()
+                .await
+                .unwrap();
+        }
+    }
+
+    #[test]
+    fn only_the_focused_task_is_written() {
+        let lines = lines_of(
+            Layer::builder().with_focus(Focus::task_name("a")),
+            spawn_a_and_b(),
+        );
+
+        let events = lines
+            .iter()
+            .filter(|line| line.ends_with(" polled"))
+            .collect::<Vec<_>>();
+        assert_eq!(events.len(), 1);
+        assert!(events[0].contains("task.name=a"));
+        assert!(events[0].ends_with(r#"name="a" polled"#));
+        assert!(lines.iter().any(|line| line.ends_with("} new")));
+        assert!(!lines.iter().any(|line| line.contains("task.name=b")));
+    }
+
+    #[test]
+    fn spawn_locations_select_tasks() {
+        let lines = lines_of(
+            Layer::builder().with_focus(Focus::spawn_location(file!())),
+            spawn_a_and_b(),
+        );
+
+        assert!(lines.iter().any(|line| line.contains("task.name=a")));
+        assert!(lines.iter().any(|line| line.contains("task.name=b")));
+        // The task running the test's future was spawned elsewhere, so its lines are left out.
+        assert!(lines
+            .iter()
+            .all(|line| line.contains("task.name=a") || line.contains("task.name=b")));
+    }
+}
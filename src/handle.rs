@@ -0,0 +1,125 @@
+//! A handle to a [`Layer`] which has been added to a registry.
+//!
+//! See the documentation on [`Handle`] for more details.
+//!
+//! [`Layer`]: crate::Layer
+use std::{io, sync::Arc};
+
+use tracing_subscriber::fmt::MakeWriter;
+
+use crate::{focus::SharedFocus, recorder::Recorder, summary::Summary, Focus};
+
+/// A handle to a [`Layer`], created with [`Layer::handle`].
+///
+/// Once a layer has been added to a registry, it can no longer be accessed directly. A handle
+/// shares the layer's state and writer, so it can be used to write reports about the run while
+/// it is in progress. Handles can be cloned and sent to other threads.
+///
+/// [`Layer`]: crate::Layer
+/// [`Layer::handle`]: crate::Layer::handle
+pub struct Handle<W = fn() -> io::Stdout>
+where
+    W: for<'w> MakeWriter<'w> + 'static,
+{
+    summary: Option<Arc<Summary>>,
+    recorder: Option<Arc<Recorder>>,
+    focus: Arc<SharedFocus>,
+    make_writer: Arc<W>,
+    ansi: bool,
+}
+
+impl<W> Handle<W>
+where
+    W: for<'w> MakeWriter<'w> + 'static,
+{
+    pub(crate) fn new(
+        summary: Option<Arc<Summary>>,
+        recorder: Option<Arc<Recorder>>,
+        focus: Arc<SharedFocus>,
+        make_writer: Arc<W>,
+        ansi: bool,
+    ) -> Self {
+        Self {
+            summary,
+            recorder,
+            focus,
+            make_writer,
+            ansi,
+        }
+    }
+
+    /// Writes out the lines kept by the flight recorder, leaving it empty.
+    ///
+    /// Does nothing unless the flight recorder was enabled with
+    /// [`Builder::with_flight_recorder`].
+    ///
+    /// [`Builder::with_flight_recorder`]: crate::Builder::with_flight_recorder
+    pub fn dump(&self) {
+        if let Some(recorder) = &self.recorder {
+            recorder.dump(&*self.make_writer);
+        }
+    }
+
+    /// Installs a panic hook which writes out the lines kept by the flight recorder before
+    /// calling the previously installed panic hook.
+    ///
+    /// Does nothing unless the flight recorder was enabled with
+    /// [`Builder::with_flight_recorder`].
+    ///
+    /// [`Builder::with_flight_recorder`]: crate::Builder::with_flight_recorder
+    pub fn dump_on_panic(&self)
+    where
+        W: Send + Sync,
+    {
+        let Some(recorder) = self.recorder.clone() else {
+            return;
+        };
+        let make_writer = Arc::clone(&self.make_writer);
+        let previous_hook = std::panic::take_hook();
+        std::panic::set_hook(Box::new(move |info| {
+            recorder.dump(&*make_writer);
+            previous_hook(info);
+        }));
+    }
+
+    /// Writes the list of task, resource, and async op spans which have been created but not
+    /// closed, with their ids, locations, ages, and last lifecycle messages.
+    ///
+    /// The list is only written if the tracking needed for it was enabled, with either
+    /// [`Builder::with_leak_detection`] or [`Builder::with_summary`].
+    ///
+    /// [`Builder::with_leak_detection`]: crate::Builder::with_leak_detection
+    /// [`Builder::with_summary`]: crate::Builder::with_summary
+    pub fn write_leaks(&self) {
+        if let Some(summary) = &self.summary {
+            summary.write_leaks(&*self.make_writer, self.ansi);
+        }
+    }
+
+    /// Follows the task selected by `focus`, replacing the current focus if there is one.
+    ///
+    /// Lines which are already being written aren't affected. See [`Focus`] for details.
+    pub fn set_focus(&self, focus: Focus) {
+        self.focus.set(Some(focus));
+    }
+
+    /// Clears the focus, so that the output of every task is written again.
+    pub fn clear_focus(&self) {
+        self.focus.set(None);
+    }
+}
+
+impl<W> Clone for Handle<W>
+where
+    W: for<'w> MakeWriter<'w> + 'static,
+{
+    fn clone(&self) -> Self {
+        Self {
+            summary: self.summary.clone(),
+            recorder: self.recorder.clone(),
+            focus: Arc::clone(&self.focus),
+            make_writer: Arc::clone(&self.make_writer),
+            ansi: self.ansi,
+        }
+    }
+}
@@ -1,13 +1,38 @@
-//! A [`tracing-subscriber`] Layer which outputs to `stdout`.
+//! A [`tracing-subscriber`] Layer which outputs to `stdout` (or any other writer).
 //!
 //! See the documentation on [`Layer`] for more details.
 //!
 //! [`tracing-subscriber`]: tracing_subscriber
+use std::{
+    cell::{Cell, RefCell},
+    io::{self, Write},
+    sync::Arc,
+    time::Duration,
+};
+
 use chrono::{DateTime, Utc};
-use tracing::{span, subscriber::Interest, Subscriber};
-use tracing_subscriber::registry::LookupSpan;
+use thread_local::ThreadLocal;
+use tracing::{level_filters::LevelFilter, span, Metadata, Subscriber};
+use tracing_subscriber::{
+    fmt::MakeWriter,
+    registry::{LookupSpan, Scope, SpanRef},
+};
 
-use crate::fmt::{FmtEvent, FmtFields, FmtSpan};
+#[cfg(any(test, feature = "test-support"))]
+use crate::recording::Recording;
+use crate::{
+    builder::{resolve_ansi, writer_is_terminal},
+    capture::{Capture, Occurrence},
+    fmt::{
+        format_waker_op, ChromeTrace, Deterministic, Emphasis, FieldValue, FmtConfig, FmtEvent,
+        FmtFields, FmtSpan, Format, Palette, PendingFlows, Readiness, SpanEvents, SpanKind, Track,
+    },
+    focus::SharedFocus,
+    recorder::Recorder,
+    stats::{format_duration, AsyncOpStats, TaskStats},
+    summary::{Summary, SummaryGuard},
+    Builder, Clock, Directives, Handle, KindFilter,
+};
 
 /// Creates a new [`Layer`].
 ///
@@ -32,17 +57,51 @@ pub fn layer() -> Layer {
 /// A [`tracing-subscriber`] Layer which outputs to `stdout`.
 ///
 /// The layer can be added to a [`Registry`] and will output trace information
-/// to `stdout`.
+/// to `stdout`. The output destination and format can be customized by creating the layer with
+/// [`Layer::builder`].
 ///
 /// [`tracing-subscriber`]: tracing_subscriber
 /// [`Registry`]: struct@tracing_subscriber::Registry
-
-pub struct Layer {}
+pub struct Layer<W = fn() -> io::Stdout>
+where
+    W: for<'w> MakeWriter<'w> + 'static,
+{
+    make_writer: Arc<W>,
+    clock: Clock,
+    format: Format,
+    chrome_trace: Option<ChromeTrace>,
+    /// Whether ANSI escape codes were enabled or disabled explicitly, the detection is made
+    /// again if the writer is replaced.
+    ansi: Option<bool>,
+    config: FmtConfig,
+    span_events: SpanEvents,
+    max_level: LevelFilter,
+    kind_filter: KindFilter,
+    directives: Directives,
+    /// The tasks being polled on each thread, the innermost last. Resources and async ops are
+    /// often entered in a different task than the one they were created in.
+    polling: ThreadLocal<RefCell<Vec<span::Id>>>,
+    /// The task last polled on each thread, if it finished that poll with a lost waker.
+    lost_waker_suspects: Option<ThreadLocal<Cell<Option<span::Id>>>>,
+    busy_poll_threshold: Option<Duration>,
+    /// The long poll last exited on each thread, which is only warned about on the next callback
+    /// in case it was the drop of the task's future.
+    long_polls: ThreadLocal<Cell<Option<LongPoll>>>,
+    sched_latency_threshold: Option<Duration>,
+    summary: Option<Arc<Summary>>,
+    recorder: Option<Arc<Recorder>>,
+    capture: Option<Capture>,
+    focus: Arc<SharedFocus>,
+    /// Records the lifecycle of spans for tests, instead of writing any lines.
+    #[cfg(any(test, feature = "test-support"))]
+    recording: Option<Recording>,
+}
 
 impl Layer {
     /// Creates a new [`Layer`].
     ///
-    /// Currently, no customization is possible.
+    /// The layer is created with the default configuration, use [`Layer::builder`] to customize
+    /// it.
     ///
     /// # Examples
     ///
@@ -59,22 +118,266 @@ impl Layer {
     /// ```
     #[must_use = "A Layer does nothing if it is not added to a registry."]
     pub fn new() -> Self {
-        Self {}
+        Self::builder().build()
+    }
+
+    /// Creates a [`Builder`] to configure a new [`Layer`].
+    ///
+    /// See the [`Builder`] documentation for the available options.
+    ///
+    /// # Examples
+    ///
+    /// ```rust
+    /// use tracing_subscriber::prelude::*;
+    ///
+    /// use ari_subscriber::Layer;
+    ///
+    /// let layer = Layer::builder().with_writer(std::io::stderr).build();
+    /// tracing_subscriber::registry().with(layer).init();
+    ///
+    /// // Will be printed to `stderr` by `ari_subscriber`
+    /// tracing::info!("splendid!");
+    /// ```
+    #[must_use]
+    pub fn builder() -> Builder {
+        Builder::default()
     }
 }
 
 impl Default for Layer {
-    #[must_use = "A Layer does nothing if it is not added to a registry."]
     fn default() -> Self {
         Self::new()
     }
 }
 
-impl Layer {
-    // Self kept because it will be needed with pending changes.
-    #[allow(clippy::unused_self)]
-    fn write_event(&self, fmt_event: &mut FmtEvent) {
-        println!("{}", fmt_event.formatted());
+impl<W> Layer<W>
+where
+    W: for<'w> MakeWriter<'w> + 'static,
+{
+    pub(crate) fn from_builder(builder: Builder<W>) -> Self {
+        let ansi = builder.resolve_ansi();
+        let clock = builder.resolve_clock();
+        let start = clock.now();
+        let chrome_trace = (builder.format == Format::ChromeTrace).then(|| {
+            write_chrome_header(&builder.make_writer);
+            ChromeTrace::new(start)
+        });
+        Self {
+            make_writer: Arc::new(builder.make_writer),
+            clock: clock.clone(),
+            format: builder.format,
+            chrome_trace,
+            ansi: builder.ansi,
+            config: FmtConfig {
+                ansi,
+                timestamp: builder.timestamp,
+                scope_style: builder.scope_style,
+                palette: builder.palette,
+                start,
+                deterministic: builder
+                    .deterministic
+                    .then(|| Arc::new(Deterministic::new())),
+            },
+            span_events: builder.span_events,
+            max_level: builder.max_level,
+            kind_filter: builder.kind_filter,
+            directives: builder.directives,
+            polling: ThreadLocal::new(),
+            lost_waker_suspects: builder.lost_waker_detection.then(ThreadLocal::new),
+            busy_poll_threshold: builder.busy_poll_threshold,
+            long_polls: ThreadLocal::new(),
+            sched_latency_threshold: builder.sched_latency_threshold,
+            // The reports are text, which would make the other formats invalid.
+            summary: ((builder.summary || builder.leak_detection)
+                && builder.format == Format::Text)
+                .then(|| {
+                    Arc::new(Summary::new(
+                        builder.summary,
+                        builder.leak_detection,
+                        clock.clone(),
+                    ))
+                }),
+            // A trigger keeps its own pre-trigger buffer, which takes the place of the flight
+            // recorder.
+            recorder: builder
+                .flight_recorder
+                .filter(|_| builder.trigger.is_none())
+                .map(|limit| Arc::new(Recorder::new(limit))),
+            capture: builder.trigger.map(|trigger| Capture::new(trigger, clock)),
+            focus: Arc::new(SharedFocus::new(builder.focus)),
+            #[cfg(any(test, feature = "test-support"))]
+            recording: None,
+        }
+    }
+
+    /// Records into `recording`, which replaces the output, lines are neither formatted nor
+    /// written.
+    #[cfg(any(test, feature = "test-support"))]
+    pub(crate) fn with_recording(mut self, recording: Recording) -> Self {
+        self.recording = Some(recording);
+        self
+    }
+
+    /// Sets the [`MakeWriter`] that the [`Layer`] will use to write its output.
+    ///
+    /// The rest of the layer's configuration is kept, except that unless ANSI escape codes were
+    /// enabled or disabled explicitly, whether they are used is detected again for the new
+    /// writer. Handles created before the writer was replaced keep writing to the previous
+    /// writer. With [`Format::ChromeTrace`], the header of
+    /// the trace is written again to the new writer.
+    ///
+    /// # Examples
+    ///
+    /// ```rust
+    /// # #![allow(deprecated)]
+    /// use ari_subscriber::Layer;
+    ///
+    /// let layer = Layer::new().with_writer(std::io::stderr);
+    /// ```
+    #[deprecated(note = "use `Builder::with_writer` instead")]
+    #[must_use = "A Layer does nothing if it is not added to a registry."]
+    pub fn with_writer<W2>(mut self, make_writer: W2) -> Layer<W2>
+    where
+        W2: for<'w> MakeWriter<'w> + 'static,
+    {
+        if self.chrome_trace.is_some() {
+            write_chrome_header(&make_writer);
+        }
+        let config = FmtConfig {
+            ansi: resolve_ansi(self.ansi, writer_is_terminal::<W2>()),
+            ..self.config.clone()
+        };
+        // `self` is dropped at the end, taking the summary stops it from being written then.
+        Layer {
+            make_writer: Arc::new(make_writer),
+            clock: self.clock.clone(),
+            format: self.format,
+            chrome_trace: self.chrome_trace.take(),
+            ansi: self.ansi,
+            config,
+            span_events: self.span_events,
+            max_level: self.max_level,
+            kind_filter: self.kind_filter.clone(),
+            directives: self.directives.clone(),
+            polling: std::mem::take(&mut self.polling),
+            lost_waker_suspects: self.lost_waker_suspects.take(),
+            busy_poll_threshold: self.busy_poll_threshold,
+            long_polls: std::mem::take(&mut self.long_polls),
+            sched_latency_threshold: self.sched_latency_threshold,
+            summary: self.summary.take(),
+            recorder: self.recorder.take(),
+            capture: self.capture.take(),
+            focus: Arc::clone(&self.focus),
+            #[cfg(any(test, feature = "test-support"))]
+            recording: self.recording.take(),
+        }
+    }
+
+    /// Returns a [`Handle`] to the layer, which can be used to inspect it after it has been added
+    /// to a registry.
+    ///
+    /// # Examples
+    ///
+    /// ```rust
+    /// use tracing_subscriber::prelude::*;
+    ///
+    /// let layer = ari_subscriber::Layer::builder()
+    ///     .with_leak_detection(true)
+    ///     .build();
+    /// let handle = layer.handle();
+    /// tracing_subscriber::registry().with(layer).init();
+    ///
+    /// // Lists the task, resource, and async op spans which are still open.
+    /// handle.write_leaks();
+    /// ```
+    pub fn handle(&self) -> Handle<W> {
+        Handle::new(
+            self.summary.clone(),
+            self.recorder.clone(),
+            Arc::clone(&self.focus),
+            Arc::clone(&self.make_writer),
+            self.config.ansi,
+        )
+    }
+
+    /// Returns a guard which writes the summary report when it is dropped.
+    ///
+    /// The summary must be enabled with [`Builder::with_summary`], otherwise dropping the guard
+    /// does nothing. See [`SummaryGuard`] for details.
+    pub fn summary_guard(&self) -> SummaryGuard<W> {
+        SummaryGuard::new(
+            self.summary.clone(),
+            Arc::clone(&self.make_writer),
+            self.config.ansi,
+        )
+    }
+
+    fn write_event<'s, S>(
+        &self,
+        meta: &Metadata<'_>,
+        fmt_event: &mut FmtEvent,
+        scope: Option<Scope<'s, S>>,
+        woken: Option<SpanRef<'s, S>>,
+    ) where
+        S: Subscriber + for<'a> LookupSpan<'a>,
+    {
+        #[cfg(any(test, feature = "test-support"))]
+        if self.recording.is_some() {
+            return;
+        }
+        let scope = scope
+            .map(|scope| scope.from_root().collect::<Vec<_>>())
+            .unwrap_or_default();
+        let Some(emphasis) = self.emphasis(&scope, woken.as_ref()) else {
+            return;
+        };
+        if self.config.palette == Palette::Task {
+            if let Some(task) = scope.iter().rev().find(|span| is_task(span)) {
+                if let Some(fmt_span) = task.extensions().get::<FmtSpan>() {
+                    fmt_event.inherit_colors(fmt_span);
+                }
+            }
+        }
+        let line = match self.format {
+            Format::Text => {
+                let formatted_scope = Self::formatted_scope(&scope);
+                Some(fmt_event.formatted(&self.config, &formatted_scope, emphasis))
+            }
+            Format::Json => Some(fmt_event.json(json_scope(&scope))),
+            // Trace events are written by `trace_slice` and `trace_event` instead.
+            Format::ChromeTrace => None,
+        };
+        if let Some(line) = line {
+            self.write(meta, line);
+        }
+        self.dump_recorder(fmt_event);
+    }
+
+    /// Writes out the lines kept by the flight recorder if the event is a warning or an error.
+    fn dump_recorder(&self, fmt_event: &FmtEvent<'_>) {
+        if let Some(recorder) = &self.recorder {
+            if fmt_event.level() <= tracing::Level::WARN {
+                recorder.dump(&*self.make_writer);
+            }
+        }
+    }
+
+    fn write(&self, meta: &Metadata<'_>, mut line: String) {
+        line.push('\n');
+        if let Some(capture) = &self.capture {
+            capture.write(line, &*self.make_writer);
+            return;
+        }
+        if let Some(recorder) = &self.recorder {
+            recorder.record(line);
+            return;
+        }
+
+        // The whole line is written at once, so that the writer is only locked a single time
+        // and lines from different threads don't get interleaved.
+        let mut writer = self.make_writer.make_writer_for(meta);
+        // There is nowhere to report a failure to write, so the error is dropped.
+        let _ = writer.write_all(line.as_bytes());
     }
 
     fn span_event<S>(
@@ -82,6 +385,7 @@ impl Layer {
         now: DateTime<Utc>,
         id: &span::Id,
         ctx: &tracing_subscriber::layer::Context<'_, S>,
+        span_event: SpanEvents,
         message: String,
     ) where
         S: Subscriber + for<'a> LookupSpan<'a>,
@@ -89,53 +393,614 @@ impl Layer {
         let span = ctx.span(id).expect("Span not found, this is a bug");
         let extensions = span.extensions();
         let Some(fmt_span) = extensions.get::<FmtSpan>() else {
-            // We can't print anything if the fmt_span isn't present.
+            // We can't print anything if the fmt_span isn't present, which is also the case for
+            // spans which aren't selected.
             return;
         };
 
-        let formatted_scope = ctx
-            .span_scope(id)
-            .map(|scope| self.formatted_scope(scope))
-            .unwrap_or_default();
-        let mut fmt_event =
-            FmtEvent::new_span_event(now, fmt_span, span.metadata(), &formatted_scope, message);
+        if let Some(summary) = &self.summary {
+            summary.event("span");
+            summary.lifecycle(id, &message);
+        }
+        if !self.span_events.contains(span_event) {
+            return;
+        }
+
+        let mut fmt_event = FmtEvent::new_span_event(now, fmt_span, span.metadata(), message);
+        if span_event == SpanEvents::EXIT {
+            if let Some(readiness) = fmt_span.readiness() {
+                fmt_event.annotate_readiness(readiness);
+            }
+        }
+        self.write_event(span.metadata(), &mut fmt_event, ctx.span_scope(id), None);
+    }
+
+    /// Writes a warning about a span, with the span's scope.
+    fn warning<S>(
+        &self,
+        now: DateTime<Utc>,
+        id: &span::Id,
+        ctx: &tracing_subscriber::layer::Context<'_, S>,
+        label: &'static str,
+        message: String,
+    ) where
+        S: Subscriber + for<'a> LookupSpan<'a>,
+    {
+        let Some(span) = ctx.span(id) else {
+            return;
+        };
+        if let Some(summary) = &self.summary {
+            summary.event("warning");
+        }
+        let mut fmt_event = FmtEvent::new_warning(now, span.metadata(), label, message);
+        #[cfg(any(test, feature = "test-support"))]
+        if let Some(recording) = &self.recording {
+            recording.on_warning(&fmt_event, span.metadata());
+        }
+        self.write_event(span.metadata(), &mut fmt_event, ctx.span_scope(id), None);
+    }
+
+    /// Calls `f` with the span's [`FmtSpan`], if the span still exists and has one.
+    fn with_fmt_span<S, R>(
+        id: &span::Id,
+        ctx: &tracing_subscriber::layer::Context<'_, S>,
+        f: impl FnOnce(&mut FmtSpan) -> R,
+    ) -> Option<R>
+    where
+        S: Subscriber + for<'a> LookupSpan<'a>,
+    {
+        let span = ctx.span(id)?;
+        let mut extensions = span.extensions_mut();
+        extensions.get_mut::<FmtSpan>().map(f)
+    }
+
+    /// Records the result of a `poll_op` event against the closest async op poll span in its
+    /// scope.
+    ///
+    /// A single poll may record more than one `poll_op` event, the last one is the result of
+    /// the poll.
+    fn record_poll_op<S>(
+        is_ready: bool,
+        event: &tracing::Event<'_>,
+        ctx: &tracing_subscriber::layer::Context<'_, S>,
+    ) where
+        S: Subscriber + for<'a> LookupSpan<'a>,
+    {
+        let Some(mut scope) = ctx.event_scope(event) else {
+            return;
+        };
+        let readiness = if is_ready {
+            Readiness::Ready { pending_polls: 0 }
+        } else {
+            Readiness::Pending
+        };
+        scope.find(|span| {
+            let mut extensions = span.extensions_mut();
+            match extensions.get_mut::<FmtSpan>() {
+                Some(fmt_span) if matches!(fmt_span.kind(), SpanKind::AsyncOpPoll) => {
+                    fmt_span.set_readiness(Some(readiness));
+                    true
+                }
+                _ => false,
+            }
+        });
+    }
+
+    /// Counts the result of a finished poll against the async op which the poll span polls,
+    /// updating the number of `Pending` polls before the op was ready.
+    fn end_async_op_poll<S>(id: &span::Id, ctx: &tracing_subscriber::layer::Context<'_, S>)
+    where
+        S: Subscriber + for<'a> LookupSpan<'a>,
+    {
+        let Some(readiness) =
+            Self::with_fmt_span(id, ctx, |fmt_span| fmt_span.readiness()).flatten()
+        else {
+            return;
+        };
+        let is_ready = matches!(readiness, Readiness::Ready { .. });
+        let Some(pending_polls) = ctx.span_scope(id).and_then(|mut scope| {
+            scope.find_map(|span| {
+                span.extensions_mut()
+                    .get_mut::<FmtSpan>()
+                    .and_then(FmtSpan::async_op_stats_mut)
+                    .map(|async_op_stats| async_op_stats.poll_op(is_ready))
+            })
+        }) else {
+            return;
+        };
+        if is_ready {
+            Self::with_fmt_span(id, ctx, |fmt_span| {
+                fmt_span.set_readiness(Some(Readiness::Ready { pending_polls }));
+            });
+        }
+    }
+
+    /// Applies a resource state update event to the closest resource span in its scope.
+    ///
+    /// Returns a description of the state transitions.
+    fn update_resource_state<S>(
+        &self,
+        fmt_event: &FmtEvent<'_>,
+        event: &tracing::Event<'_>,
+        ctx: &tracing_subscriber::layer::Context<'_, S>,
+    ) -> Option<String>
+    where
+        S: Subscriber + for<'a> LookupSpan<'a>,
+    {
+        ctx.event_scope(event)?.find_map(|span| {
+            span.extensions_mut()
+                .get_mut::<FmtSpan>()
+                .and_then(|fmt_span| fmt_span.update_state(fmt_event.fields(), &self.config))
+        })
+    }
+
+    /// Records a resource being entered against the task being polled.
+    fn resource_entered<S>(&self, id: &span::Id, ctx: &tracing_subscriber::layer::Context<'_, S>)
+    where
+        S: Subscriber + for<'a> LookupSpan<'a>,
+    {
+        if self.busy_poll_threshold.is_none() {
+            return;
+        }
+        let Some(resource) = Self::with_fmt_span(id, ctx, |fmt_span| {
+            matches!(fmt_span.kind(), SpanKind::Resource).then(|| fmt_span.resource_name())
+        })
+        .flatten() else {
+            return;
+        };
+        // Resources are often created in one task and entered while polling another.
+        if let Some(task_id) = self
+            .current_task()
+            .or_else(|| closest_task(ctx.span_scope(id)))
+        {
+            Self::with_fmt_span(&task_id, ctx, |fmt_span| {
+                if let Some(task_stats) = fmt_span.task_stats_mut() {
+                    task_stats.resource_entered(resource);
+                }
+            });
+        }
+    }
+
+    /// Marks a task span which is being entered as being polled on the current thread.
+    fn poll_started<S>(&self, id: &span::Id, ctx: &tracing_subscriber::layer::Context<'_, S>)
+    where
+        S: Subscriber + for<'a> LookupSpan<'a>,
+    {
+        if ctx.span(id).map_or(false, |span| is_task(&span)) {
+            self.polling.get_or_default().borrow_mut().push(id.clone());
+        }
+    }
+
+    /// Marks a span which is being exited as no longer being polled, if it is a task.
+    fn poll_ended(&self, id: &span::Id) {
+        let Some(polling) = self.polling.get() else {
+            return;
+        };
+        let mut polling = polling.borrow_mut();
+        if let Some(index) = polling.iter().rposition(|task_id| task_id == id) {
+            polling.remove(index);
+        }
+    }
+
+    /// Returns the id of the task being polled on the current thread, if there is one.
+    fn current_task(&self) -> Option<span::Id> {
+        self.polling
+            .get()
+            .and_then(|polling| polling.borrow().last().cloned())
+    }
+
+    /// Returns the track in a Chrome trace of the task being polled on the current thread.
+    ///
+    /// Outside a poll, this is the track of the closest task span in the scope, or otherwise
+    /// the track of the current thread.
+    fn task_track<S>(&self, scope: Option<Scope<'_, S>>) -> Track
+    where
+        S: Subscriber + for<'a> LookupSpan<'a>,
+    {
+        self.current_task()
+            .or_else(|| closest_task(scope))
+            .map_or_else(Track::current_thread, |id| {
+                Track::Task(self.config.span_id(&id))
+            })
+    }
+
+    /// Returns `true` if the span or event with this metadata is selected by the maximum level,
+    /// the kind filter, and the level and target directives.
+    fn selected(&self, meta: &Metadata<'_>) -> bool {
+        self.max_level >= *meta.level()
+            && self.kind_filter.enabled(meta)
+            && self.directives.enabled(meta)
+    }
+
+    /// Fires the trigger, if there is one and the occurrence matches it.
+    fn check_trigger(&self, occurrence: &Occurrence<'_>) {
+        if let Some(capture) = &self.capture {
+            capture.check(occurrence, &*self.make_writer);
+        }
+    }
+
+    /// Keeps a task's poll which took longer than the busy poll threshold, to be warned about by
+    /// [`Self::check_busy_poll`].
+    fn suspect_busy_poll<S>(
+        &self,
+        now: DateTime<Utc>,
+        id: &span::Id,
+        ctx: &tracing_subscriber::layer::Context<'_, S>,
+        poll_duration: Duration,
+    ) where
+        S: Subscriber + for<'a> LookupSpan<'a>,
+    {
+        let Some(threshold) = self.busy_poll_threshold else {
+            return;
+        };
+        if poll_duration <= threshold {
+            return;
+        }
+
+        let Some(message) = Self::with_fmt_span(id, ctx, |fmt_span| {
+            let location = fmt_span
+                .location()
+                .unwrap_or_else(|| "an unknown location".into());
+            let resources = fmt_span
+                .task_stats()
+                .map(TaskStats::poll_resources)
+                .unwrap_or_default();
+            let resources = if resources.is_empty() {
+                "no resources entered".to_owned()
+            } else {
+                format!("entered {resources}", resources = resources.join(", "))
+            };
+            format!(
+                "task spawned at {location} was busy for {duration} in a single poll, {resources}",
+                duration = format_duration(poll_duration),
+            )
+        }) else {
+            return;
+        };
+        self.long_polls.get_or_default().set(Some(LongPoll {
+            id: id.clone(),
+            exited: now,
+            message,
+        }));
+    }
+
+    /// Writes a warning for the long poll last exited on this thread, if there is one.
+    ///
+    /// Tokio enters a task's span one last time to drop its future, which isn't a poll. That
+    /// enter is exited right before the span is closed on the same thread. So this check is made
+    /// on the next callback on the same thread, which passes the span it is `closing`, if any. If
+    /// that is the task of the long poll, there is nothing to warn about.
+    fn check_busy_poll<S>(
+        &self,
+        closing: Option<&span::Id>,
+        ctx: &tracing_subscriber::layer::Context<'_, S>,
+    ) where
+        S: Subscriber + for<'a> LookupSpan<'a>,
+    {
+        let Some(long_poll) = self.long_polls.get().and_then(Cell::take) else {
+            return;
+        };
+        if closing == Some(&long_poll.id) {
+            return;
+        }
+        self.warning(
+            long_poll.exited,
+            &long_poll.id,
+            ctx,
+            "long poll",
+            long_poll.message,
+        );
+    }
+
+    /// Writes a warning if a task waited longer than the scheduling latency threshold between
+    /// being woken and being polled.
+    fn check_sched_latency<S>(
+        &self,
+        now: DateTime<Utc>,
+        id: &span::Id,
+        ctx: &tracing_subscriber::layer::Context<'_, S>,
+        sched_latency: Duration,
+    ) where
+        S: Subscriber + for<'a> LookupSpan<'a>,
+    {
+        let Some(threshold) = self.sched_latency_threshold else {
+            return;
+        };
+        if sched_latency <= threshold {
+            return;
+        }
+
+        let Some(location) = Self::with_fmt_span(id, ctx, |fmt_span| {
+            fmt_span
+                .location()
+                .unwrap_or_else(|| "an unknown location".into())
+        }) else {
+            return;
+        };
+        let message = format!(
+            "task spawned at {location} waited {latency} to be polled after being woken",
+            latency = format_duration(sched_latency),
+        );
+        self.warning(now, id, ctx, "scheduling latency", message);
+    }
+
+    /// Marks a task which has just been exited as suspected of having lost its waker, if it has
+    /// no live wakers and hasn't been woken.
+    ///
+    /// The `block_on` task is never suspected, Tokio doesn't instrument the waker passed to the
+    /// future it blocks on, so its waker operations can't be counted.
+    fn suspect_lost_waker<S>(&self, id: &span::Id, ctx: &tracing_subscriber::layer::Context<'_, S>)
+    where
+        S: Subscriber + for<'a> LookupSpan<'a>,
+    {
+        let Some(suspects) = &self.lost_waker_suspects else {
+            return;
+        };
+        let lost_waker = ctx.span(id).map_or(false, |span| {
+            span.extensions()
+                .get::<FmtSpan>()
+                .map_or(false, |fmt_span| {
+                    fmt_span.field("kind").and_then(FieldValue::as_str) != Some("block_on")
+                        && fmt_span.task_stats().map_or(false, TaskStats::lost_waker)
+                })
+        });
+        if lost_waker {
+            suspects.get_or_default().set(Some(id.clone()));
+        }
+    }
 
-        self.write_event(&mut fmt_event);
+    /// Writes a warning for the task last exited on this thread if it has lost its waker.
+    ///
+    /// The poll which completes a task also leaves it without wakers, but it is immediately
+    /// followed by the task's span being entered to drop the future and then closed. So this
+    /// check is made on the next callback on the same thread, which is passed the `current` span
+    /// that the callback is for. If that is the suspected task, there is nothing to warn about.
+    fn check_lost_waker<S>(
+        &self,
+        now: DateTime<Utc>,
+        current: Option<&span::Id>,
+        ctx: &tracing_subscriber::layer::Context<'_, S>,
+    ) where
+        S: Subscriber + for<'a> LookupSpan<'a>,
+    {
+        let Some(suspects) = &self.lost_waker_suspects else {
+            return;
+        };
+        let Some(id) = suspects.get().and_then(Cell::take) else {
+            return;
+        };
+        if current == Some(&id) {
+            return;
+        }
+
+        // The task may have been woken from another thread in the meantime.
+        let still_lost = ctx.span(&id).map_or(false, |span| {
+            span.extensions()
+                .get::<FmtSpan>()
+                .and_then(FmtSpan::task_stats)
+                .map_or(false, TaskStats::lost_waker)
+        });
+        if still_lost {
+            self.warning(
+                now,
+                &id,
+                ctx,
+                "lost waker",
+                "task was polled and has no wakers and wasn't woken, it will never be polled again"
+                    .into(),
+            );
+        }
+    }
+
+    /// Returns how a line with this scope (or waking this task) is displayed, or `None` if it
+    /// is left out. Lines are only left out or dimmed if a [`Focus`] is set.
+    ///
+    /// [`Focus`]: crate::Focus
+    fn emphasis<'s, S>(
+        &self,
+        scope: &[SpanRef<'s, S>],
+        woken: Option<&SpanRef<'s, S>>,
+    ) -> Option<Emphasis>
+    where
+        S: Subscriber + for<'a> LookupSpan<'a>,
+    {
+        let focus = self.focus.get();
+        let Some(focus) = focus.as_ref() else {
+            return Some(Emphasis::Full);
+        };
+        let in_focus = scope.iter().chain(woken).any(|span| {
+            span.extensions()
+                .get::<FmtSpan>()
+                .map_or(false, |fmt_span| focus.selects(fmt_span))
+        });
+        if in_focus {
+            Some(Emphasis::Full)
+        } else if focus.dimming() {
+            Some(Emphasis::Dimmed)
+        } else {
+            None
+        }
     }
 
-    // Self kept because it will be needed with pending changes.
-    #[allow(clippy::unused_self)]
-    fn formatted_scope<S>(&self, scope: tracing_subscriber::registry::Scope<'_, S>) -> String
+    fn formatted_scope<S>(scope: &[SpanRef<'_, S>]) -> String
     where
         S: Subscriber + for<'a> LookupSpan<'a>,
     {
         let mut formatted_scope = String::new();
-        for span in scope.from_root() {
+        for span in scope {
             let extensions = span.extensions();
-            let span = extensions
-                .get::<FmtSpan>()
-                .expect("cannot get fields for in-scope span. This is a bug!");
+            // Spans which aren't selected are left out of the scope.
+            let Some(span) = extensions.get::<FmtSpan>() else {
+                continue;
+            };
             formatted_scope.push_str(&format!("{span} ", span = span.formatted()));
         }
         formatted_scope
     }
+
+    /// Writes the beginning or end of a slice for a span entered or exited in a Chrome trace.
+    fn trace_slice<S>(
+        &self,
+        chrome_trace: &ChromeTrace,
+        now: DateTime<Utc>,
+        id: &span::Id,
+        ctx: &tracing_subscriber::layer::Context<'_, S>,
+        phase: &str,
+    ) where
+        S: Subscriber + for<'a> LookupSpan<'a>,
+    {
+        let span = ctx.span(id).expect("Span not found, this is a bug");
+        let is_task = is_task(&span);
+        // A task's polls are always on its own track, even when it is polled within another.
+        let track = if is_task {
+            Track::Task(self.config.span_id(id))
+        } else {
+            self.task_track(ctx.span_scope(id))
+        };
+        {
+            let extensions = span.extensions();
+            let Some(fmt_span) = extensions.get::<FmtSpan>() else {
+                return;
+            };
+            let slice = chrome_trace.slice(phase, now, fmt_span, track);
+            self.write(span.metadata(), ChromeTrace::line(&slice));
+        }
+
+        if is_task && phase == "B" {
+            // Wakes for this task end at the beginning of its next poll.
+            let flows = span
+                .extensions_mut()
+                .get_mut::<PendingFlows>()
+                .map(PendingFlows::take)
+                .unwrap_or_default();
+            for flow_id in flows {
+                let flow_end = chrome_trace.flow_end(flow_id, now, track);
+                self.write(span.metadata(), ChromeTrace::line(&flow_end));
+            }
+        }
+    }
+
+    /// Writes an event to a Chrome trace, wakes also start a flow to the woken task.
+    fn trace_event<S>(
+        &self,
+        chrome_trace: &ChromeTrace,
+        fmt_event: &FmtEvent<'_>,
+        event: &tracing::Event<'_>,
+        ctx: &tracing_subscriber::layer::Context<'_, S>,
+    ) where
+        S: Subscriber + for<'a> LookupSpan<'a>,
+    {
+        let track = self.task_track(ctx.event_scope(event));
+        let instant = chrome_trace.instant(fmt_event, track);
+        self.write(event.metadata(), ChromeTrace::line(&instant));
+
+        let Some((op, task_id)) = fmt_event.waker_op() else {
+            return;
+        };
+        let Some(task_span) = ctx.span(&task_id) else {
+            return;
+        };
+        if op.is_wake() {
+            let (flow_id, flow_start) = chrome_trace.flow_start(fmt_event.timestamp(), track);
+            self.write(event.metadata(), ChromeTrace::line(&flow_start));
+
+            let mut extensions = task_span.extensions_mut();
+            if let Some(flows) = extensions.get_mut::<PendingFlows>() {
+                flows.push(flow_id);
+            } else {
+                let mut flows = PendingFlows::default();
+                flows.push(flow_id);
+                extensions.insert(flows);
+            }
+        }
+    }
+}
+
+/// A poll which took longer than the busy poll threshold, see [`Layer::check_busy_poll`].
+struct LongPoll {
+    id: span::Id,
+    exited: DateTime<Utc>,
+    message: String,
 }
 
-impl<S> tracing_subscriber::Layer<S> for Layer
+/// Marks a span which was left out by the [`Directives`], instead of a [`FmtSpan`].
+struct Ignored;
+
+/// Returns `true` if the span was left out by the [`Directives`].
+fn is_ignored<S>(span: &SpanRef<'_, S>) -> bool
 where
     S: Subscriber + for<'a> LookupSpan<'a>,
 {
-    fn register_callsite(&self, _metadata: &'static tracing::Metadata<'static>) -> Interest {
-        Interest::always()
-    }
+    span.extensions().get::<Ignored>().is_some()
+}
 
-    fn enabled(
-        &self,
-        _metadata: &tracing::Metadata<'_>,
-        _ctx: tracing_subscriber::layer::Context<'_, S>,
-    ) -> bool {
-        true
+/// Returns `true` if the span is a task span.
+fn is_task<S>(span: &SpanRef<'_, S>) -> bool
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+{
+    span.extensions()
+        .get::<FmtSpan>()
+        .map_or(false, |fmt_span| matches!(fmt_span.kind(), SpanKind::Spawn))
+}
+
+/// Returns the id of the closest task span in the scope.
+pub(crate) fn closest_task<S>(scope: Option<Scope<'_, S>>) -> Option<span::Id>
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+{
+    scope
+        .into_iter()
+        .flatten()
+        .find(|span| is_task(span))
+        .map(|span| span.id())
+}
+
+/// Writes the header of a Chrome trace directly to the writer.
+///
+/// The header is written as soon as the layer is created, so that no other line can be written
+/// before it, even from another thread.
+fn write_chrome_header<W>(make_writer: &W)
+where
+    W: for<'w> MakeWriter<'w>,
+{
+    // There is nowhere to report a failure to write, so the error is dropped.
+    let _ = make_writer
+        .make_writer()
+        .write_all(ChromeTrace::header().as_bytes());
+}
+
+fn json_scope<S>(scope: &[SpanRef<'_, S>]) -> Vec<serde_json::Value>
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+{
+    scope
+        .iter()
+        .filter_map(|span| span.extensions().get::<FmtSpan>().map(FmtSpan::json))
+        .collect()
+}
+
+impl<W> Drop for Layer<W>
+where
+    W: for<'w> MakeWriter<'w> + 'static,
+{
+    fn drop(&mut self) {
+        if let Some(summary) = &self.summary {
+            summary.write(&*self.make_writer, self.config.ansi);
+        }
     }
+}
+
+impl<S, W> tracing_subscriber::Layer<S> for Layer<W>
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+    W: for<'w> MakeWriter<'w> + 'static,
+{
+    // `register_callsite` and `enabled` aren't implemented, as returning `Interest::never()` or
+    // `false` would disable the callsite for every layer in the registry, not just this one. So
+    // spans and events which aren't selected are dropped in `on_new_span` and `on_event` instead.
+    // Callsites can be disabled for this layer alone with a `KindFilter` or `Directives` added
+    // with `with_filter`.
 
     fn on_new_span(
         &self,
@@ -143,50 +1008,699 @@ where
         id: &span::Id,
         ctx: tracing_subscriber::layer::Context<'_, S>,
     ) {
-        let now = Utc::now();
+        if !self.selected(attrs.metadata()) {
+            return;
+        }
+        let now = self.clock.now();
+        self.check_lost_waker(now, None, &ctx);
+        self.check_busy_poll(None, &ctx);
         let span = ctx.span(id).expect("Span not found, this is a bug");
         {
-            let mut extensions = span.extensions_mut();
+            let kind = SpanKind::from_metadata(attrs.metadata());
+            // Tasks are selected by their own spawn location, other spans are left out along
+            // with the span they were created in.
+            let within_ignored = kind != SpanKind::Spawn
+                && span
+                    .parent()
+                    .or_else(|| ctx.lookup_current())
+                    .map_or(false, |parent| is_ignored(&parent));
+            let mut fields = FmtFields::new_span();
+            attrs.record(&mut fields);
+            if within_ignored || !self.directives.span_selected(&kind, &fields) {
+                span.extensions_mut().insert(Ignored);
+                return;
+            }
 
+            // With the task palette, spans are colored after the task they were created in.
+            let task = (self.config.palette == Palette::Task)
+                .then(|| {
+                    let parent = span.parent().or_else(|| ctx.lookup_current())?;
+                    closest_task(Some(parent.scope())).and_then(|task_id| ctx.span(&task_id))
+                })
+                .flatten();
+            let task_extensions = task.as_ref().map(SpanRef::extensions);
+            let task = task_extensions
+                .as_ref()
+                .and_then(|extensions| extensions.get::<FmtSpan>());
+
+            let mut extensions = span.extensions_mut();
             if extensions.get_mut::<FmtSpan>().is_none() {
-                let mut fields = FmtFields::new_span();
-                attrs.record(&mut fields);
                 fields.format();
-                let span = FmtSpan::new(id, attrs, fields);
+                let span = FmtSpan::new(now, id, attrs, fields, task, &self.config);
+                if let Some(summary) = &self.summary {
+                    summary.new_span(now, id, &span);
+                }
+                if matches!(span.kind(), SpanKind::Spawn) {
+                    let location = span.location();
+                    self.check_trigger(&Occurrence::Spawn {
+                        location: location.as_deref(),
+                    });
+                }
                 extensions.insert(span);
             }
         }
+        #[cfg(any(test, feature = "test-support"))]
+        if let Some(recording) = &self.recording {
+            recording.on_new_span(id, &ctx);
+        }
+
+        if self.chrome_trace.is_some() {
+            let extensions = span.extensions();
+            if let Some(fmt_span) = extensions.get::<FmtSpan>() {
+                if matches!(fmt_span.kind(), SpanKind::Spawn) {
+                    let track_name = ChromeTrace::track_name(fmt_span);
+                    self.write(span.metadata(), ChromeTrace::line(&track_name));
+                }
+            }
+            return;
+        }
+
+        self.span_event(now, id, &ctx, SpanEvents::NEW, "new".into());
+    }
+
+    fn on_record(
+        &self,
+        id: &span::Id,
+        values: &span::Record<'_>,
+        ctx: tracing_subscriber::layer::Context<'_, S>,
+    ) {
+        Self::with_fmt_span(id, &ctx, |fmt_span| fmt_span.record(values, &self.config));
+    }
+
+    fn on_follows_from(
+        &self,
+        id: &span::Id,
+        follows: &span::Id,
+        ctx: tracing_subscriber::layer::Context<'_, S>,
+    ) {
+        let now = self.clock.now();
+        // The span being followed may already have been closed, in which case only its id is
+        // known.
+        let follows_name = ctx
+            .span(follows)
+            .and_then(|span| {
+                span.extensions()
+                    .get::<FmtSpan>()
+                    .map(|fmt_span| fmt_span.name().to_owned())
+            })
+            .unwrap_or_else(|| "span".to_owned());
+        let message = format!(
+            "follows {follows_name}[{follows_id}]",
+            follows_id = self.config.span_id(follows)
+        );
+
+        Self::with_fmt_span(id, &ctx, |fmt_span| {
+            fmt_span.follows_from(follows_name, follows, &self.config);
+        });
 
-        self.span_event(now, id, &ctx, "new".into());
+        if self.chrome_trace.is_some() {
+            return;
+        }
+        self.span_event(now, id, &ctx, SpanEvents::FOLLOWS, message);
     }
 
     fn on_event(&self, event: &tracing::Event<'_>, ctx: tracing_subscriber::layer::Context<'_, S>) {
-        let now = Utc::now();
+        if !self.selected(event.metadata()) {
+            return;
+        }
+        let now = self.clock.now();
+        self.check_lost_waker(now, None, &ctx);
+        self.check_busy_poll(None, &ctx);
 
         let mut fields = FmtFields::new_event();
         event.record(&mut fields);
 
-        let formatted_scope = ctx
-            .event_scope(event)
-            .map(|scope| self.formatted_scope(scope))
-            .unwrap_or_default();
+        let mut fmt_event = FmtEvent::new(now, event.metadata(), fields);
+        if let Some((op, task_id)) = fmt_event.waker_op() {
+            Self::with_fmt_span(&task_id, &ctx, |fmt_span| {
+                if let Some(task_stats) = fmt_span.task_stats_mut() {
+                    task_stats.waker_op(op, now);
+                }
+            });
+        }
+        // Waker operations within spans which were left out by the directives still count
+        // towards the task's wakers above, but nothing else is done with them. The same goes for
+        // the waker operations of tasks which were left out.
+        let ignored = ctx
+            .event_span(event)
+            .map_or(false, |parent| is_ignored(&parent))
+            || fmt_event
+                .waker_op()
+                .and_then(|(_, task_id)| ctx.span(&task_id))
+                .map_or(false, |task| is_ignored(&task));
+        if ignored {
+            return;
+        }
+        self.check_trigger(&Occurrence::Event {
+            target: event.metadata().target(),
+        });
+
+        fmt_event.display_task_id(&self.config);
+        if let Some(summary) = &self.summary {
+            summary.event(fmt_event.kind().name());
+        }
+        #[cfg(any(test, feature = "test-support"))]
+        if let Some(recording) = &self.recording {
+            recording.on_event(&fmt_event, event, &ctx);
+        }
+        if let Some(is_ready) = fmt_event.poll_op_readiness() {
+            Self::record_poll_op(is_ready, event, &ctx);
+        }
+        let state_transitions = fmt_event
+            .is_state_update()
+            .then(|| self.update_resource_state(&fmt_event, event, &ctx))
+            .flatten();
+        if let Some(chrome_trace) = &self.chrome_trace {
+            self.trace_event(chrome_trace, &fmt_event, event, &ctx);
+            self.dump_recorder(&fmt_event);
+            return;
+        }
+        #[cfg(any(test, feature = "test-support"))]
+        if self.recording.is_some() {
+            return;
+        }
+
+        if let Some(state_transitions) = state_transitions {
+            fmt_event.describe(state_transitions);
+        }
 
-        let mut fmt_event = FmtEvent::new(now, event.metadata(), &formatted_scope, fields);
-        self.write_event(&mut fmt_event);
+        if let Some((op, task_id)) = fmt_event.waker_op() {
+            // The waker may belong to a resource created in a different task than the one being
+            // polled, which is the task that the waker operation comes from.
+            let source = self
+                .current_task()
+                .or_else(|| closest_task(ctx.event_scope(event)));
+            let task_span = ctx.span(&task_id);
+            let extensions = task_span.as_ref().map(|span| span.extensions());
+            let description = format_waker_op(
+                op,
+                &task_id,
+                extensions.as_ref().and_then(|ext| ext.get::<FmtSpan>()),
+                source.as_ref(),
+                &self.config,
+            );
+            fmt_event.describe(description);
+        }
+        let woken = fmt_event
+            .waker_op()
+            .and_then(|(_, task_id)| ctx.span(&task_id));
+        self.write_event(
+            event.metadata(),
+            &mut fmt_event,
+            ctx.event_scope(event),
+            woken,
+        );
     }
 
     fn on_enter(&self, id: &span::Id, ctx: tracing_subscriber::layer::Context<'_, S>) {
-        let now: DateTime<Utc> = Utc::now();
-        self.span_event(now, id, &ctx, "enter".into());
+        let now: DateTime<Utc> = self.clock.now();
+        self.check_lost_waker(now, Some(id), &ctx);
+        self.check_busy_poll(None, &ctx);
+        self.poll_started(id, &ctx);
+        let sched_latency = Self::with_fmt_span(id, &ctx, |fmt_span| {
+            fmt_span.set_readiness(None);
+            fmt_span
+                .task_stats_mut()
+                .and_then(|task_stats| task_stats.poll_start(now))
+        })
+        .flatten();
+        self.resource_entered(id, &ctx);
+        #[cfg(any(test, feature = "test-support"))]
+        if let Some(recording) = &self.recording {
+            recording.on_enter(id, &ctx);
+        }
+        if let Some(chrome_trace) = &self.chrome_trace {
+            self.trace_slice(chrome_trace, now, id, &ctx, "B");
+            return;
+        }
+        let message = match sched_latency {
+            Some(sched_latency) => format!("enter (sched {})", format_duration(sched_latency)),
+            None => "enter".into(),
+        };
+        self.span_event(now, id, &ctx, SpanEvents::ENTER, message);
+        if let Some(sched_latency) = sched_latency {
+            self.check_sched_latency(now, id, &ctx, sched_latency);
+        }
     }
 
     fn on_exit(&self, id: &span::Id, ctx: tracing_subscriber::layer::Context<'_, S>) {
-        let now: DateTime<Utc> = Utc::now();
-        self.span_event(now, id, &ctx, "exit".into());
+        let now: DateTime<Utc> = self.clock.now();
+        self.check_lost_waker(now, Some(id), &ctx);
+        self.check_busy_poll(None, &ctx);
+        self.poll_ended(id);
+        let poll_duration = Self::with_fmt_span(id, &ctx, |fmt_span| {
+            fmt_span
+                .task_stats_mut()
+                .and_then(|task_stats| task_stats.poll_end(now))
+        })
+        .flatten();
+        if let Some(poll_duration) = poll_duration {
+            if let Some(summary) = &self.summary {
+                summary.poll(id, poll_duration);
+            }
+            self.check_trigger(&Occurrence::Poll {
+                duration: poll_duration,
+            });
+        }
+        Self::end_async_op_poll(id, &ctx);
+        #[cfg(any(test, feature = "test-support"))]
+        if let Some(recording) = &self.recording {
+            recording.on_exit(id, poll_duration.is_some(), &ctx);
+        }
+        if let Some(chrome_trace) = &self.chrome_trace {
+            self.trace_slice(chrome_trace, now, id, &ctx, "E");
+            return;
+        }
+        self.span_event(now, id, &ctx, SpanEvents::EXIT, "exit".into());
+        if let Some(poll_duration) = poll_duration {
+            self.suspect_busy_poll(now, id, &ctx, poll_duration);
+        }
+        self.suspect_lost_waker(id, &ctx);
     }
 
     fn on_close(&self, id: span::Id, ctx: tracing_subscriber::layer::Context<'_, S>) {
-        let now: DateTime<Utc> = Utc::now();
-        self.span_event(now, &id, &ctx, "close".into());
+        let drop_duration = Self::with_fmt_span(&id, &ctx, |fmt_span| {
+            fmt_span.task_stats_mut().and_then(TaskStats::close)
+        })
+        .flatten();
+        if let Some(summary) = &self.summary {
+            if let Some(drop_duration) = drop_duration {
+                summary.discard_poll(&id, drop_duration);
+            }
+            summary.close_span(&id);
+        }
+        #[cfg(any(test, feature = "test-support"))]
+        if let Some(recording) = &self.recording {
+            recording.on_close(&id, &ctx);
+        }
+        // The end of a span's last poll has already been written in a Chrome trace.
+        if self.chrome_trace.is_none() {
+            let now: DateTime<Utc> = self.clock.now();
+            self.check_lost_waker(now, Some(&id), &ctx);
+            self.check_busy_poll(Some(&id), &ctx);
+            let summary = ctx.span(&id).and_then(|span| {
+                let extensions = span.extensions();
+                let fmt_span = extensions.get::<FmtSpan>()?;
+                fmt_span
+                    .task_stats()
+                    .map(|task_stats| task_stats.summary(now))
+                    .or_else(|| fmt_span.async_op_stats().map(AsyncOpStats::summary))
+            });
+            let message = match summary {
+                Some(summary) => format!("close {summary}"),
+                None => "close".into(),
+            };
+            self.span_event(now, &id, &ctx, SpanEvents::CLOSE, message);
+        }
+        if let Some(deterministic) = &self.config.deterministic {
+            deterministic.close_span(&id);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        future::{poll_fn, Future},
+        io,
+        task::Poll,
+        time::Duration,
+    };
+
+    use tracing::Level;
+    use tracing_subscriber::{fmt::MakeWriter, prelude::*};
+
+    use crate::{
+        test_support::{run, Output, Recording},
+        Builder, Directives, EventKind, KindFilter, Layer, SpanKind,
+    };
+
+    /// Runs `future` on a current thread runtime, with a layer built from `builder` which records
+    /// into the returned recording.
+    fn record<W>(builder: Builder<W>, future: impl Future<Output = ()>) -> Recording
+    where
+        W: for<'w> MakeWriter<'w> + Send + Sync + 'static,
+    {
+        let recording = Recording::default();
+        let layer = builder.build().with_recording(recording.clone());
+        run(tracing_subscriber::registry().with(layer), future);
+        recording
+    }
+
+    async fn spawn_and_sleep() {
+        tokio::task::Builder::new()
+            .name("sleeper")
+            .spawn(tokio::time::sleep(Duration::from_millis(1)))
+            .unwrap()
+            .await
+            .unwrap();
+    }
+
+    #[test]
+    fn recording_replaces_output() {
+        let output = Output::default();
+        let recording = record(
+            Layer::builder().with_writer(output.clone()),
+            spawn_and_sleep(),
+        );
+
+        assert!(recording.task_named("sleeper").is_some());
+        assert!(output.contents().is_empty());
+    }
+
+    #[test]
+    #[allow(deprecated)]
+    fn replacing_the_writer_keeps_explicit_ansi() {
+        let output = Output::default();
+        let layer = Layer::builder()
+            .with_ansi(true)
+            .build()
+            .with_writer(output.clone());
+        run(tracing_subscriber::registry().with(layer), async {
+            tracing::info!("colored");
+        });
+        assert!(output.contents().contains('\x1b'));
+
+        let output = Output::default();
+        let layer = Layer::builder()
+            .with_ansi(false)
+            .build()
+            .with_writer(output.clone());
+        run(tracing_subscriber::registry().with(layer), async {
+            tracing::info!("plain");
+        });
+        assert!(!output.contents().contains('\x1b'));
+    }
+
+    #[test]
+    fn task_polls() {
+        let recording = record(Layer::builder(), async {
+            tokio::task::Builder::new()
+                .name("yielder")
+                .spawn(async {
+                    tokio::task::yield_now().await;
+                    tokio::task::yield_now().await;
+                })
+                .unwrap()
+                .await
+                .unwrap();
+        });
+
+        // The enter to drop the task's future isn't counted as a poll.
+        let task = recording.task_named("yielder").unwrap();
+        assert!(task.is_closed());
+        assert_eq!(task.polls, 3);
+        assert_eq!(recording.wakes_of(&task).len(), 2);
+    }
+
+    #[test]
+    fn resource_state_updates() {
+        let recording = record(Layer::builder(), async {
+            let semaphore = tokio::sync::Semaphore::new(1);
+            let _permit = semaphore.acquire().await.unwrap();
+        });
+
+        let semaphore = &recording.resources_of_type("Semaphore")[0];
+        assert!(semaphore.dropped.is_some());
+        assert!(!recording
+            .events_of_kind(&EventKind::ResourceStateUpdate)
+            .is_empty());
+    }
+
+    #[test]
+    fn kind_filter() {
+        let filter = KindFilter::none().include_span(SpanKind::Spawn);
+        let recording = record(Layer::builder().with_kind_filter(filter), spawn_and_sleep());
+
+        assert!(recording.task_named("sleeper").is_some());
+        assert!(recording.resources().is_empty());
+        assert!(recording.async_ops().is_empty());
+        assert!(recording.events_of_kind(&EventKind::Waker).is_empty());
+    }
+
+    #[test]
+    fn max_level() {
+        let recording = record(
+            Layer::builder().with_max_level(Level::INFO),
+            spawn_and_sleep(),
+        );
+
+        assert!(recording.tasks().is_empty());
+        assert!(recording.events().is_empty());
+    }
+
+    #[test]
+    fn spawn_location_directives() {
+        let directives = Directives::parse("loc=src/net/**").unwrap();
+        let recording = record(
+            Layer::builder().with_directives(directives),
+            spawn_and_sleep(),
+        );
+        assert!(recording.tasks().is_empty());
+
+        let directives = Directives::parse("loc=src/*.rs").unwrap();
+        let recording = record(
+            Layer::builder().with_directives(directives),
+            spawn_and_sleep(),
+        );
+        assert!(recording.task_named("sleeper").is_some());
+    }
+
+    #[test]
+    fn filtering_leaves_other_layers_alone() {
+        let filtered = Layer::builder()
+            .with_writer(io::sink)
+            .with_kind_filter(KindFilter::none())
+            .with_directives(Directives::parse("level=error").unwrap())
+            .build();
+        let recording = Recording::default();
+        let recorder = Layer::builder().build().with_recording(recording.clone());
+        run(
+            tracing_subscriber::registry().with(filtered).with(recorder),
+            async {
+                spawn_and_sleep().await;
+                tracing::info!("not an error");
+            },
+        );
+
+        assert!(recording.task_named("sleeper").is_some());
+        assert_eq!(recording.resources_of_type("Sleep").len(), 1);
+        assert_eq!(recording.events_of_kind(&EventKind::Unknown).len(), 1);
+    }
+
+    /// Spawns a task which returns `Pending` without keeping its waker, so it is never polled
+    /// again.
+    async fn lose_waker() {
+        tokio::task::Builder::new()
+            .name("lost")
+            .spawn(poll_fn(|_| Poll::<()>::Pending))
+            .unwrap();
+        tokio::time::sleep(Duration::from_millis(1)).await;
+    }
+
+    #[test]
+    fn lost_waker() {
+        let recording = record(
+            Layer::builder().with_lost_waker_detection(true),
+            lose_waker(),
+        );
+
+        let warnings = recording.events_of_kind(&EventKind::Warning("lost waker"));
+        assert_eq!(warnings.len(), 1);
+        assert_eq!(warnings[0].target, "tokio::task");
+    }
+
+    #[test]
+    fn lost_waker_detection_is_opt_in() {
+        let recording = record(Layer::builder(), lose_waker());
+
+        assert!(recording
+            .events_of_kind(&EventKind::Warning("lost waker"))
+            .is_empty());
+    }
+
+    #[test]
+    fn no_lost_waker_for_completed_or_block_on_tasks() {
+        let recording = record(
+            Layer::builder().with_lost_waker_detection(true),
+            spawn_and_sleep(),
+        );
+
+        assert!(recording.task_named("sleeper").unwrap().is_closed());
+        assert!(recording
+            .events_of_kind(&EventKind::Warning("lost waker"))
+            .is_empty());
+    }
+
+    /// Blocks the thread for longer than the busy poll threshold used in the tests.
+    fn busy() {
+        std::thread::sleep(Duration::from_millis(20));
+    }
+
+    /// Blocks the thread when it is dropped.
+    struct SlowDrop;
+
+    impl Drop for SlowDrop {
+        fn drop(&mut self) {
+            busy();
+        }
+    }
+
+    #[test]
+    fn busy_poll() {
+        let recording = record(
+            Layer::builder().with_busy_poll_threshold(Duration::from_millis(10)),
+            async {
+                tokio::spawn(async { busy() }).await.unwrap();
+            },
+        );
+
+        assert_eq!(
+            recording
+                .events_of_kind(&EventKind::Warning("long poll"))
+                .len(),
+            1
+        );
+    }
+
+    #[test]
+    fn no_busy_poll_for_dropping_a_task() {
+        let recording = record(
+            Layer::builder().with_busy_poll_threshold(Duration::from_millis(10)),
+            async {
+                let task = tokio::spawn(async {
+                    let _slow_drop = SlowDrop;
+                    std::future::pending::<()>().await;
+                });
+                tokio::task::yield_now().await;
+                task.abort();
+                let _ = task.await;
+            },
+        );
+
+        assert!(recording
+            .events_of_kind(&EventKind::Warning("long poll"))
+            .is_empty());
+    }
+
+    #[test]
+    fn busy_poll_resources_are_those_of_the_polling_task() {
+        let output = Output::default();
+        let layer = Layer::builder()
+            .with_writer(output.clone())
+            .with_ansi(false)
+            .with_busy_poll_threshold(Duration::from_millis(10))
+            .build();
+        run(tracing_subscriber::registry().with(layer), async {
+            // The sleep is created outside the task which polls it.
+            let sleep = tokio::time::sleep(Duration::from_millis(1));
+            tokio::spawn(async {
+                busy();
+                sleep.await;
+            })
+            .await
+            .unwrap();
+        });
+
+        let lines = output.lines();
+        let warnings = lines
+            .iter()
+            .filter(|line| line.contains("in a single poll"))
+            .collect::<Vec<_>>();
+        assert_eq!(warnings.len(), 1);
+        assert!(warnings[0].contains("entered Sleep["), "{}", warnings[0]);
+    }
+
+    /// Returns the span id of the task with this name from its `new` line.
+    fn task_span_id(lines: &[String], name: &str) -> String {
+        let new_line = lines
+            .iter()
+            .find(|line| line.contains(&format!("task.name={name},")) && line.ends_with(" new"))
+            .unwrap();
+        let start = new_line.rfind("runtime.spawn[").unwrap() + "runtime.spawn[".len();
+        let len = new_line[start..].find(']').unwrap();
+        new_line[start..start + len].to_owned()
+    }
+
+    #[test]
+    fn waker_op_sources() {
+        let output = Output::default();
+        let layer = Layer::builder()
+            .with_writer(output.clone())
+            .with_ansi(false)
+            .build();
+        run(tracing_subscriber::registry().with(layer), async {
+            // The channel is created outside both tasks which use it.
+            let (tx, rx) = tokio::sync::oneshot::channel::<()>();
+            let receiver = tokio::task::Builder::new()
+                .name("receiver")
+                .spawn(async { rx.await.unwrap() })
+                .unwrap();
+            tokio::task::yield_now().await;
+            tokio::task::Builder::new()
+                .name("sender")
+                .spawn(async { tx.send(()).unwrap() })
+                .unwrap()
+                .await
+                .unwrap();
+            receiver.await.unwrap();
+        });
+
+        let lines = output.lines();
+        let receiver = task_span_id(&lines, "receiver");
+        let sender = task_span_id(&lines, "sender");
+        let waker_ops = lines
+            .iter()
+            .filter(|line| line.contains("tokio::task::waker: "))
+            .filter(|line| line.contains(&format!(" task[{receiver}] ")))
+            .collect::<Vec<_>>();
+        // The receiver keeps its own waker while it is polled, and is woken by the sender.
+        assert!(waker_ops
+            .iter()
+            .any(|line| line.contains("clone task[") && line.ends_with(" by itself")));
+        assert!(waker_ops
+            .iter()
+            .any(|line| line.contains("wake") && line.ends_with(&format!(" from task[{sender}]"))));
+    }
+
+    #[test]
+    fn per_layer_filters_disable_callsites() {
+        let layer = Layer::builder()
+            .with_writer(io::sink)
+            .build()
+            .with_filter(KindFilter::none().include_span(SpanKind::Spawn));
+        run(tracing_subscriber::registry().with(layer), async {
+            assert!(!tracing::enabled!(Level::ERROR));
+        });
+
+        let layer = Layer::builder()
+            .with_writer(io::sink)
+            .build()
+            .with_filter(Directives::parse("tasks,level=warn").unwrap());
+        run(tracing_subscriber::registry().with(layer), async {
+            assert!(tracing::enabled!(Level::WARN));
+            assert!(!tracing::enabled!(Level::INFO));
+        });
+    }
+
+    #[test]
+    fn per_layer_filters_leave_other_layers_alone() {
+        let filtered = Layer::builder()
+            .with_writer(io::sink)
+            .build()
+            .with_filter(KindFilter::none());
+        let recording = Recording::default();
+        let recorder = Layer::builder().build().with_recording(recording.clone());
+        run(
+            tracing_subscriber::registry().with(filtered).with(recorder),
+            async {
+                spawn_and_sleep().await;
+                tracing::info!("not selected");
+            },
+        );
+
+        assert!(recording.task_named("sleeper").is_some());
+        assert_eq!(recording.resources_of_type("Sleep").len(), 1);
+        assert_eq!(recording.events_of_kind(&EventKind::Unknown).len(), 1);
     }
 }
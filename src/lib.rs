@@ -1,9 +1,9 @@
 //! The *Async Runtime Instrumentation Subscriber* is a [`tracing-subscriber`] optimized for
 //! visually debugging Tokio tracing instrumentation.
 //!
-//! This crate provides a [`Layer`] which writes [`tracing`] information to `stdout`. It colorizes
-//! the traces that result from the tracing instrumentation in Tokio to make identifying them
-//! easier.
+//! This crate provides a [`Layer`] which writes [`tracing`] information to `stdout` (or any other
+//! writer). It colorizes the traces that result from the tracing instrumentation in Tokio to make
+//! identifying them easier.
 //!
 //! # Usage
 //!
@@ -67,6 +67,12 @@
 //! <span style='opacity:0.67'><b><span style='color:#aaa'>2023-11-28</span></b></span><span style='opacity:0.67'>T<b><span style='color:#aaa'>10:06:44</span></b></span><span style='opacity:0.67'>.748196Z</span> <span style='color:#9d4edd'>TRACE</span> <span style='color:#489e6c'>runtime.spawn[<b><span style='color:#5aba84'>1</span></b></span><span style='color:#489e6c'>]{kind=task, task.name=, task.id=18, loc.file=&quot;examples/tokio-task.rs&quot;, loc.line=14, loc.col=5}</span> <span style='color:#ba5a57'>runtime.resource[<b><span style='color:#df5853'>274877906945</span></b></span><span style='color:#ba5a57'>]{concrete_type=&quot;Sleep&quot;, kind=&quot;timer&quot;, loc.file=&quot;examples/tokio-task.rs&quot;, loc.line=15, loc.col=9}</span> <span style='color:#5c8dce'>runtime.resource.async_op[<b><span style='color:#508ee3'>274877906946</span></b></span><span style='color:#5c8dce'>]{source=&quot;Sleep::new_timeout&quot;}</span> <span style='color:#e5e44d'>runtime.resource.async_op.poll[<b><span style='color:#f5f466'>274877906947</span></b></span><span style='color:#e5e44d'>]{}</span> <b><u><span style='color:#f5f466'>new</span></u></b>
 //! </pre>
 //!
+//! ## Testing
+//!
+//! With the `test-support` feature enabled, the `test_support` module provides a layer which
+//! records the lifecycle of tasks, resources, and async ops instead of printing them, so that
+//! tests can assert on the runtime's behaviour.
+//!
 //! ## Comparison with `tracing-subscriber`
 //!
 //! `ari_subscriber` is built on top of `tracing-subscriber` and uses its registry (as do the
@@ -103,7 +109,31 @@
 //! [`fmt::Subscriber`]: struct@tracing_subscriber::fmt::Subscriber
 #![deny(rustdoc::missing_crate_level_docs, missing_docs)]
 
+mod builder;
+mod capture;
+mod clock;
+mod directives;
+mod filter;
 pub(crate) mod fmt;
+mod focus;
+mod handle;
 mod layer;
+mod recorder;
+#[cfg(any(test, feature = "test-support"))]
+mod recording;
+mod stats;
+mod summary;
+#[cfg(any(test, feature = "test-support"))]
+pub mod test_support;
 
+pub use builder::Builder;
+pub use capture::{CaptureLength, Trigger};
+pub use clock::Clock;
+pub use directives::{Directives, ParseError, DEFAULT_ENV};
+pub use filter::KindFilter;
+pub use fmt::{EventKind, Format, Palette, ScopeStyle, SpanEvents, SpanKind, Timestamp};
+pub use focus::Focus;
+pub use handle::Handle;
 pub use layer::{layer, Layer};
+pub use recorder::RecorderLimit;
+pub use summary::SummaryGuard;
@@ -0,0 +1,141 @@
+//! A flight recorder, which keeps the most recent lines in memory instead of writing them.
+//!
+//! See the documentation on [`Builder::with_flight_recorder`] for more details.
+//!
+//! [`Builder::with_flight_recorder`]: crate::Builder::with_flight_recorder
+use std::{collections::VecDeque, io::Write, sync::Mutex};
+
+use tracing_subscriber::fmt::MakeWriter;
+
+/// The amount of output kept by the flight recorder.
+///
+/// Set with [`Builder::with_flight_recorder`].
+///
+/// [`Builder::with_flight_recorder`]: crate::Builder::with_flight_recorder
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum RecorderLimit {
+    /// Keep at most this many of the most recent lines.
+    Lines(usize),
+    /// Keep as many of the most recent lines as fit in this many bytes. A single line which is
+    /// longer than the limit isn't kept.
+    Bytes(usize),
+}
+
+/// A bounded ring buffer of formatted lines.
+pub(crate) struct Recorder {
+    limit: RecorderLimit,
+    buffer: Mutex<Buffer>,
+}
+
+#[derive(Default)]
+struct Buffer {
+    lines: VecDeque<String>,
+    bytes: usize,
+}
+
+impl Recorder {
+    pub(crate) fn new(limit: RecorderLimit) -> Self {
+        Self {
+            limit,
+            buffer: Mutex::default(),
+        }
+    }
+
+    /// Records a line, including its trailing newline, evicting the oldest lines which no
+    /// longer fit.
+    pub(crate) fn record(&self, line: String) {
+        let mut buffer = self.buffer();
+        buffer.bytes += line.len();
+        buffer.lines.push_back(line);
+        while buffer.exceeds(self.limit) {
+            let Some(evicted) = buffer.lines.pop_front() else {
+                break;
+            };
+            buffer.bytes -= evicted.len();
+        }
+    }
+
+    /// Writes all the recorded lines to the writer, leaving the recorder empty.
+    pub(crate) fn dump<W>(&self, make_writer: &W)
+    where
+        W: for<'w> MakeWriter<'w> + 'static,
+    {
+        let dump = {
+            let mut buffer = self.buffer();
+            buffer.bytes = 0;
+            buffer.lines.drain(..).collect::<String>()
+        };
+        if dump.is_empty() {
+            return;
+        }
+        let mut writer = make_writer.make_writer();
+        let _ = writer.write_all(dump.as_bytes());
+    }
+
+    fn buffer(&self) -> std::sync::MutexGuard<'_, Buffer> {
+        // The buffer is still usable if a thread panicked while holding the lock, which matters
+        // as it is dumped from the panic hook.
+        self.buffer
+            .lock()
+            .unwrap_or_else(std::sync::PoisonError::into_inner)
+    }
+}
+
+impl Buffer {
+    fn exceeds(&self, limit: RecorderLimit) -> bool {
+        match limit {
+            RecorderLimit::Lines(lines) => self.lines.len() > lines,
+            RecorderLimit::Bytes(bytes) => self.bytes > bytes,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tracing_subscriber::prelude::*;
+
+    use super::RecorderLimit;
+    use crate::{test_support::Output, Format, Layer, Timestamp};
+
+    /// Emits numbered events with a warning after the fifth, returning the output of a layer
+    /// with a flight recorder which keeps 3 lines.
+    fn record_until_warning(format: Format) -> Output {
+        let output = Output::default();
+        let layer = Layer::builder()
+            .with_writer(output.clone())
+            .with_format(format)
+            .with_ansi(false)
+            .with_timestamp(Timestamp::None)
+            .with_flight_recorder(RecorderLimit::Lines(3))
+            .build();
+        tracing::subscriber::with_default(tracing_subscriber::registry().with(layer), || {
+            for n in 1..=5 {
+                tracing::info!(n, "recorded");
+            }
+            tracing::warn!("warning");
+            tracing::info!(n = 6, "after");
+        });
+        output
+    }
+
+    #[test]
+    fn warnings_dump_the_most_recent_lines() {
+        let lines = record_until_warning(Format::Text).lines();
+
+        let lines = lines
+            .iter()
+            .map(|line| line.rsplit(": ").next().unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(lines, ["n=4 recorded", "n=5 recorded", "warning"]);
+    }
+
+    #[test]
+    fn warnings_dump_chrome_traces() {
+        let contents = record_until_warning(Format::ChromeTrace).contents();
+
+        assert!(contents.contains(r#""n":5"#));
+        assert!(contents.contains(r#""name":"ari_subscriber::recorder::tests""#));
+        assert!(!contents.contains(r#""n":3"#));
+        assert!(!contents.contains(r#""n":6"#));
+    }
+}
@@ -0,0 +1,472 @@
+//! The records kept by a recording [`Layer`], see the `test_support` module.
+//!
+//! This module is only compiled for tests and with the `test-support` feature enabled, the
+//! records are public through the `test_support` module.
+//!
+//! [`Layer`]: crate::Layer
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+};
+
+use tracing::{span, Event, Metadata, Subscriber};
+use tracing_subscriber::{layer::Context, registry::LookupSpan};
+
+use crate::{
+    fmt::{location_matches, FieldValue, FmtEvent, FmtSpan, WakerOp},
+    layer::closest_task,
+    EventKind, SpanKind,
+};
+
+/// The lifecycle records of a run, which can be queried in assertions.
+///
+/// Recordings are cheap to clone, all clones share the same records.
+#[derive(Clone, Default)]
+pub struct Recording {
+    inner: Arc<Mutex<Inner>>,
+}
+
+#[derive(Default)]
+struct Inner {
+    next_seq: u64,
+    records: Vec<Record>,
+    events: Vec<EventRecord>,
+    tasks: Vec<TaskRecord>,
+    resources: Vec<ResourceRecord>,
+    async_ops: Vec<AsyncOpRecord>,
+    wakes: Vec<WakeRecord>,
+    /// The index of each open span in the records of its kind. Span ids are reused once a span
+    /// is closed.
+    open: HashMap<span::Id, usize>,
+}
+
+/// A span lifecycle callback, in the order it was recorded.
+#[derive(Clone, Debug)]
+pub struct Record {
+    /// The position of the record in the recording, shared by all records.
+    pub seq: u64,
+    /// The id of the span.
+    pub span_id: u64,
+    /// The kind of span, one of a task, resource, or async op.
+    pub kind: SpanKind,
+    /// The callback which was recorded.
+    pub lifecycle: Lifecycle,
+}
+
+/// A span lifecycle callback.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Lifecycle {
+    /// The span was created.
+    New,
+    /// The span was entered, for a task this is the start of a poll.
+    Enter,
+    /// The span was exited, for a task this is the end of a poll.
+    Exit,
+    /// The span was closed.
+    Close,
+}
+
+/// An event, including the warnings generated by ari.
+#[derive(Clone, Debug)]
+pub struct EventRecord {
+    /// The position of the event in the recording, shared with the span lifecycle records.
+    pub seq: u64,
+    /// The kind of event.
+    pub kind: EventKind,
+    /// The target of the event, for warnings this is the target of the span warned about.
+    pub target: String,
+}
+
+/// A task, from its `runtime.spawn` span.
+#[derive(Clone, Debug)]
+pub struct TaskRecord {
+    /// The id of the task's span.
+    pub span_id: u64,
+    /// The Tokio task id.
+    pub task_id: Option<u64>,
+    /// The name given to the task, if any.
+    pub name: Option<String>,
+    /// The location the task was spawned at, as `file:line`.
+    pub location: Option<String>,
+    /// The number of times the task was polled.
+    ///
+    /// Tokio also enters the task's span when the task's future is dropped. Until the task's
+    /// span is closed, that enter is counted as a poll, afterwards it is removed.
+    pub polls: u64,
+    /// The sequence number of the task's creation.
+    pub created: u64,
+    /// The sequence number of the task's span being closed, if it was.
+    pub closed: Option<u64>,
+}
+
+/// A resource, from its `runtime.resource` span.
+#[derive(Clone, Debug)]
+pub struct ResourceRecord {
+    /// The id of the resource's span.
+    pub span_id: u64,
+    /// The type of resource, such as `Sleep` or `Semaphore`.
+    pub concrete_type: Option<String>,
+    /// The location the resource was created at, as `file:line`.
+    pub location: Option<String>,
+    /// The span id of the task which created the resource, if any.
+    pub task: Option<u64>,
+    /// The sequence number of the resource's creation.
+    pub created: u64,
+    /// The sequence number of the resource being dropped, if it was.
+    pub dropped: Option<u64>,
+}
+
+/// An async op, from its `runtime.resource.async_op` span.
+#[derive(Clone, Debug)]
+pub struct AsyncOpRecord {
+    /// The id of the async op's span.
+    pub span_id: u64,
+    /// The method which created the async op, such as `Sleep::new_timeout`.
+    pub source: Option<String>,
+    /// The span id of the resource which the async op is on, if any.
+    pub resource: Option<u64>,
+    /// The span id of the task which created the async op, if any.
+    pub task: Option<u64>,
+    /// The number of polls which returned `Pending` before the async op was ready, as of when
+    /// it was closed.
+    pub pending_polls: u64,
+    /// Whether the async op was ready, as of when it was closed.
+    pub ready: bool,
+    /// The sequence number of the async op's creation.
+    pub created: u64,
+    /// The sequence number of the async op's span being closed, if it was.
+    pub closed: Option<u64>,
+}
+
+/// A wake of a task.
+#[derive(Clone, Debug)]
+pub struct WakeRecord {
+    /// The position of the wake in the recording, shared with the span lifecycle records.
+    pub seq: u64,
+    /// The span id of the woken task.
+    pub task: u64,
+    /// The span id of the task which performed the wake, if it was performed from a task.
+    pub source: Option<u64>,
+    /// Whether the task was woken by reference (`wake_by_ref`) instead of by value.
+    pub by_ref: bool,
+}
+
+impl TaskRecord {
+    /// Returns `true` if the task's span was closed, which happens when the task completes.
+    pub fn is_closed(&self) -> bool {
+        self.closed.is_some()
+    }
+}
+
+impl Recording {
+    /// Returns all the span lifecycle records, in order.
+    pub fn records(&self) -> Vec<Record> {
+        self.inner().records.clone()
+    }
+
+    /// Returns all the events, in order.
+    pub fn events(&self) -> Vec<EventRecord> {
+        self.inner().events.clone()
+    }
+
+    /// Returns the events of the given kind, in order.
+    pub fn events_of_kind(&self, kind: &EventKind) -> Vec<EventRecord> {
+        let inner = self.inner();
+        inner
+            .events
+            .iter()
+            .filter(|event| event.kind == *kind)
+            .cloned()
+            .collect()
+    }
+
+    /// Returns all the tasks, in the order they were spawned.
+    pub fn tasks(&self) -> Vec<TaskRecord> {
+        self.inner().tasks.clone()
+    }
+
+    /// Returns the first task with the given name.
+    pub fn task_named(&self, name: &str) -> Option<TaskRecord> {
+        let inner = self.inner();
+        inner
+            .tasks
+            .iter()
+            .find(|task| task.name.as_deref() == Some(name))
+            .cloned()
+    }
+
+    /// Returns the tasks spawned at a location, either `file:line` or just `file`.
+    pub fn tasks_spawned_at(&self, location: &str) -> Vec<TaskRecord> {
+        let inner = self.inner();
+        inner
+            .tasks
+            .iter()
+            .filter(|task| {
+                task.location
+                    .as_deref()
+                    .map_or(false, |spawned_at| location_matches(spawned_at, location))
+            })
+            .cloned()
+            .collect()
+    }
+
+    /// Returns all the resources, in the order they were created.
+    pub fn resources(&self) -> Vec<ResourceRecord> {
+        self.inner().resources.clone()
+    }
+
+    /// Returns the resources of a type, such as `Sleep`, in the order they were created.
+    pub fn resources_of_type(&self, concrete_type: &str) -> Vec<ResourceRecord> {
+        let inner = self.inner();
+        inner
+            .resources
+            .iter()
+            .filter(|resource| resource.concrete_type.as_deref() == Some(concrete_type))
+            .cloned()
+            .collect()
+    }
+
+    /// Returns all the async ops, in the order they were created.
+    pub fn async_ops(&self) -> Vec<AsyncOpRecord> {
+        self.inner().async_ops.clone()
+    }
+
+    /// Returns all the wakes, in order.
+    pub fn wakes(&self) -> Vec<WakeRecord> {
+        self.inner().wakes.clone()
+    }
+
+    /// Returns the wakes of a task, in order.
+    pub fn wakes_of(&self, task: &TaskRecord) -> Vec<WakeRecord> {
+        let inner = self.inner();
+        inner
+            .wakes
+            .iter()
+            .filter(|wake| wake.task == task.span_id)
+            .cloned()
+            .collect()
+    }
+
+    /// Records a new task, resource, or async op span, along with the task (and resource) it
+    /// was created in.
+    pub(crate) fn on_new_span<S>(&self, id: &span::Id, ctx: &Context<'_, S>)
+    where
+        S: Subscriber + for<'a> LookupSpan<'a>,
+    {
+        let Some(span) = ctx.span(id) else {
+            return;
+        };
+        let extensions = span.extensions();
+        let Some(fmt_span) = extensions.get::<FmtSpan>() else {
+            return;
+        };
+        // Resources are often created without a parent, so the creating task is found from the
+        // current span instead.
+        let task = ctx
+            .current_span()
+            .id()
+            .and_then(|current| closest_task(ctx.span_scope(current)));
+        let resource = span.parent().map(|parent| parent.id()).filter(|parent| {
+            ctx.span(parent).map_or(false, |parent| {
+                parent
+                    .extensions()
+                    .get::<FmtSpan>()
+                    .map_or(false, |fmt_span| {
+                        matches!(fmt_span.kind(), SpanKind::Resource)
+                    })
+            })
+        });
+
+        let field = |name| {
+            fmt_span
+                .field(name)
+                .and_then(FieldValue::as_str)
+                .map(ToOwned::to_owned)
+        };
+        let mut inner = self.inner();
+        let seq = inner.lifecycle(id, fmt_span.kind(), Lifecycle::New);
+        let index = match fmt_span.kind() {
+            SpanKind::Spawn => {
+                inner.tasks.push(TaskRecord {
+                    span_id: id.into_u64(),
+                    task_id: fmt_span.field("task.id").and_then(FieldValue::as_u64),
+                    name: field("task.name").filter(|name| !name.is_empty()),
+                    location: fmt_span.location(),
+                    polls: 0,
+                    created: seq,
+                    closed: None,
+                });
+                inner.tasks.len() - 1
+            }
+            SpanKind::Resource => {
+                inner.resources.push(ResourceRecord {
+                    span_id: id.into_u64(),
+                    concrete_type: fmt_span.concrete_type().map(ToOwned::to_owned),
+                    location: fmt_span.location(),
+                    task: task.as_ref().map(span::Id::into_u64),
+                    created: seq,
+                    dropped: None,
+                });
+                inner.resources.len() - 1
+            }
+            SpanKind::AsyncOp => {
+                inner.async_ops.push(AsyncOpRecord {
+                    span_id: id.into_u64(),
+                    source: field("source"),
+                    resource: resource.as_ref().map(span::Id::into_u64),
+                    task: task.as_ref().map(span::Id::into_u64),
+                    pending_polls: 0,
+                    ready: false,
+                    created: seq,
+                    closed: None,
+                });
+                inner.async_ops.len() - 1
+            }
+            SpanKind::AsyncOpPoll | SpanKind::Unknown => return,
+        };
+        inner.open.insert(id.clone(), index);
+    }
+
+    pub(crate) fn on_enter<S>(&self, id: &span::Id, ctx: &Context<'_, S>)
+    where
+        S: Subscriber + for<'a> LookupSpan<'a>,
+    {
+        if let Some(kind) = span_kind(id, ctx) {
+            self.inner().lifecycle(id, &kind, Lifecycle::Enter);
+        }
+    }
+
+    /// Records the exit of a span, which is the end of a poll if the span is a task's.
+    pub(crate) fn on_exit<S>(&self, id: &span::Id, polled: bool, ctx: &Context<'_, S>)
+    where
+        S: Subscriber + for<'a> LookupSpan<'a>,
+    {
+        let Some(kind) = span_kind(id, ctx) else {
+            return;
+        };
+        let mut inner = self.inner();
+        if polled {
+            if let Some(&index) = inner.open.get(id) {
+                if let Some(task) = inner.tasks.get_mut(index) {
+                    task.polls += 1;
+                }
+            }
+        }
+        inner.lifecycle(id, &kind, Lifecycle::Exit);
+    }
+
+    pub(crate) fn on_close<S>(&self, id: &span::Id, ctx: &Context<'_, S>)
+    where
+        S: Subscriber + for<'a> LookupSpan<'a>,
+    {
+        let Some(span) = ctx.span(id) else {
+            return;
+        };
+        let extensions = span.extensions();
+        let Some(fmt_span) = extensions.get::<FmtSpan>() else {
+            return;
+        };
+        let mut inner = self.inner();
+        let seq = inner.lifecycle(id, fmt_span.kind(), Lifecycle::Close);
+        let Some(index) = inner.open.remove(id) else {
+            return;
+        };
+        match fmt_span.kind() {
+            SpanKind::Spawn => {
+                let task = &mut inner.tasks[index];
+                task.closed = Some(seq);
+                if let Some(task_stats) = fmt_span.task_stats() {
+                    task.polls = task_stats.polls();
+                }
+            }
+            SpanKind::Resource => inner.resources[index].dropped = Some(seq),
+            SpanKind::AsyncOp => {
+                let async_op = &mut inner.async_ops[index];
+                async_op.closed = Some(seq);
+                if let Some(async_op_stats) = fmt_span.async_op_stats() {
+                    async_op.pending_polls = async_op_stats.pending_polls();
+                    async_op.ready = async_op_stats.is_ready();
+                }
+            }
+            SpanKind::AsyncOpPoll | SpanKind::Unknown => {}
+        }
+    }
+
+    /// Records an event, and the wake of a task if it is a waker event.
+    pub(crate) fn on_event<S>(&self, fmt_event: &FmtEvent, event: &Event<'_>, ctx: &Context<'_, S>)
+    where
+        S: Subscriber + for<'a> LookupSpan<'a>,
+    {
+        self.event(fmt_event.kind(), event.metadata().target());
+        let Some((op, task_id)) = fmt_event.waker_op() else {
+            return;
+        };
+        if !op.is_wake() {
+            return;
+        }
+        let source = closest_task(ctx.event_scope(event));
+        let mut inner = self.inner();
+        let seq = inner.next_seq();
+        inner.wakes.push(WakeRecord {
+            seq,
+            task: task_id.into_u64(),
+            source: source.as_ref().map(span::Id::into_u64),
+            by_ref: op == WakerOp::WakeByRef,
+        });
+    }
+
+    /// Records a warning generated by ari, about the span with this metadata.
+    pub(crate) fn on_warning(&self, fmt_event: &FmtEvent, meta: &Metadata<'_>) {
+        self.event(fmt_event.kind(), meta.target());
+    }
+
+    fn event(&self, kind: &EventKind, target: &str) {
+        let mut inner = self.inner();
+        let seq = inner.next_seq();
+        inner.events.push(EventRecord {
+            seq,
+            kind: kind.clone(),
+            target: target.to_owned(),
+        });
+    }
+
+    fn inner(&self) -> std::sync::MutexGuard<'_, Inner> {
+        self.inner
+            .lock()
+            .unwrap_or_else(std::sync::PoisonError::into_inner)
+    }
+}
+
+impl Inner {
+    fn next_seq(&mut self) -> u64 {
+        let seq = self.next_seq;
+        self.next_seq += 1;
+        seq
+    }
+
+    /// Records a lifecycle callback for a task, resource, or async op span.
+    fn lifecycle(&mut self, id: &span::Id, kind: &SpanKind, lifecycle: Lifecycle) -> u64 {
+        let seq = self.next_seq();
+        if !matches!(kind, SpanKind::AsyncOpPoll | SpanKind::Unknown) {
+            self.records.push(Record {
+                seq,
+                span_id: id.into_u64(),
+                kind: kind.clone(),
+                lifecycle,
+            });
+        }
+        seq
+    }
+}
+
+/// Returns the kind of a span, if ari is recording it.
+fn span_kind<S>(id: &span::Id, ctx: &Context<'_, S>) -> Option<SpanKind>
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+{
+    let span = ctx.span(id)?;
+    let extensions = span.extensions();
+    extensions
+        .get::<FmtSpan>()
+        .map(|fmt_span| fmt_span.kind().clone())
+}
@@ -0,0 +1,274 @@
+//! Statistics collected about the Tokio tasks seen by a [`Layer`].
+//!
+//! [`Layer`]: crate::Layer
+use std::time::Duration;
+
+use chrono::{DateTime, Utc};
+
+use crate::fmt::WakerOp;
+
+/// Poll statistics for a single task, collected from the lifecycle of its span.
+pub(crate) struct TaskStats {
+    created: DateTime<Utc>,
+    first_poll: Option<DateTime<Utc>>,
+    current_poll: Option<DateTime<Utc>>,
+    polls: u64,
+    busy: Duration,
+    /// The duration of the last enter and exit of the task's span.
+    last_poll: Option<Duration>,
+    /// The number of live wakers for the task, as counted from waker clones and drops.
+    wakers: i64,
+    /// Whether the task has been woken since its last poll started.
+    woken: bool,
+    /// When the task was first woken since its last poll started.
+    woken_at: Option<DateTime<Utc>>,
+    /// The resources entered during the current (or last) poll.
+    poll_resources: Vec<String>,
+}
+
+impl TaskStats {
+    pub(crate) fn new(created: DateTime<Utc>) -> Self {
+        Self {
+            created,
+            first_poll: None,
+            current_poll: None,
+            polls: 0,
+            busy: Duration::ZERO,
+            last_poll: None,
+            wakers: 0,
+            woken: false,
+            woken_at: None,
+            poll_resources: Vec::new(),
+        }
+    }
+
+    /// Records the start of a poll, when the task's span is entered.
+    ///
+    /// Returns the scheduling latency, the time between the task first being woken and this
+    /// poll starting, if the task was woken.
+    pub(crate) fn poll_start(&mut self, now: DateTime<Utc>) -> Option<Duration> {
+        self.first_poll.get_or_insert(now);
+        self.current_poll = Some(now);
+        self.woken = false;
+        self.poll_resources.clear();
+        self.woken_at.take().map(|woken_at| elapsed(woken_at, now))
+    }
+
+    /// Records the end of a poll, when the task's span is exited, returning the duration of the
+    /// poll.
+    pub(crate) fn poll_end(&mut self, now: DateTime<Utc>) -> Option<Duration> {
+        let start = self.current_poll.take()?;
+        let poll_duration = elapsed(start, now);
+        self.polls += 1;
+        self.busy += poll_duration;
+        self.last_poll = Some(poll_duration);
+        Some(poll_duration)
+    }
+
+    /// Records that the task's span is being closed.
+    ///
+    /// Tokio enters the task's span one last time to drop the task's future, after the last
+    /// poll (or without any poll if the task was never polled). That enter and exit isn't a
+    /// poll, so it is removed from the statistics and its duration is returned.
+    pub(crate) fn close(&mut self) -> Option<Duration> {
+        let drop_duration = self.last_poll.take()?;
+        self.polls -= 1;
+        self.busy = self.busy.saturating_sub(drop_duration);
+        if self.polls == 0 {
+            self.first_poll = None;
+        }
+        Some(drop_duration)
+    }
+
+    /// Returns the number of times the task has been polled.
+    #[cfg(any(test, feature = "test-support"))]
+    pub(crate) fn polls(&self) -> u64 {
+        self.polls
+    }
+
+    /// Records that a resource was entered during the current poll.
+    pub(crate) fn resource_entered(&mut self, resource: String) {
+        if self.current_poll.is_some() && !self.poll_resources.contains(&resource) {
+            self.poll_resources.push(resource);
+        }
+    }
+
+    /// Returns the resources entered during the current (or last) poll.
+    pub(crate) fn poll_resources(&self) -> &[String] {
+        &self.poll_resources
+    }
+
+    /// Records an operation on one of the task's wakers.
+    pub(crate) fn waker_op(&mut self, op: WakerOp, now: DateTime<Utc>) {
+        if op.is_wake() {
+            self.woken_at.get_or_insert(now);
+        }
+        match op {
+            WakerOp::Clone => self.wakers += 1,
+            WakerOp::Drop => self.wakers -= 1,
+            // Waking by value consumes the waker.
+            WakerOp::Wake => {
+                self.wakers -= 1;
+                self.woken = true;
+            }
+            WakerOp::WakeByRef => self.woken = true,
+        }
+    }
+
+    /// Returns `true` if the task isn't being polled, has no live wakers, and hasn't been woken
+    /// since its last poll started. Unless it has completed, such a task will never be polled
+    /// again.
+    pub(crate) fn lost_waker(&self) -> bool {
+        // Wakers created before the task was seen can make the count negative.
+        self.current_poll.is_none() && self.polls > 0 && self.wakers <= 0 && !self.woken
+    }
+
+    /// Returns a one line summary of the task's statistics up until `now`.
+    ///
+    /// Idle time is all the time since the task was created that it wasn't being polled.
+    pub(crate) fn summary(&self, now: DateTime<Utc>) -> String {
+        let idle = elapsed(self.created, now).saturating_sub(self.busy);
+        let mut summary = format!(
+            "polls={polls} busy={busy} idle={idle}",
+            polls = self.polls,
+            busy = format_duration(self.busy),
+            idle = format_duration(idle),
+        );
+        if let Some(first_poll) = self.first_poll {
+            summary.push_str(&format!(
+                " first_poll={latency}",
+                latency = format_duration(elapsed(self.created, first_poll))
+            ));
+        }
+        summary
+    }
+}
+
+/// Poll statistics for a single async op, collected from the `poll_op` events of its polls.
+#[derive(Default)]
+pub(crate) struct AsyncOpStats {
+    /// The number of polls which returned `Pending` before the op was ready.
+    pending_polls: u64,
+    ready: bool,
+}
+
+impl AsyncOpStats {
+    /// Records the result of a finished poll of the async op, returning the number of `Pending`
+    /// polls so far.
+    pub(crate) fn poll_op(&mut self, is_ready: bool) -> u64 {
+        if is_ready {
+            self.ready = true;
+        } else if !self.ready {
+            self.pending_polls += 1;
+        }
+        self.pending_polls
+    }
+
+    #[cfg(any(test, feature = "test-support"))]
+    pub(crate) fn pending_polls(&self) -> u64 {
+        self.pending_polls
+    }
+
+    #[cfg(any(test, feature = "test-support"))]
+    pub(crate) fn is_ready(&self) -> bool {
+        self.ready
+    }
+
+    /// Returns a one line summary of the async op's polls.
+    pub(crate) fn summary(&self) -> String {
+        format!(
+            "pending_polls={pending_polls} ready={ready}",
+            pending_polls = self.pending_polls,
+            ready = self.ready,
+        )
+    }
+}
+
+/// Returns the time elapsed between `start` and `end`, or zero if the clock went backwards.
+pub(crate) fn elapsed(start: DateTime<Utc>, end: DateTime<Utc>) -> Duration {
+    (end - start).to_std().unwrap_or_default()
+}
+
+/// Formats a duration with a unit appropriate to its magnitude, e.g. `3.1ms` or `98ms`.
+pub(crate) fn format_duration(duration: Duration) -> String {
+    // The precision lost in the conversion is far below what is displayed.
+    #[allow(clippy::cast_precision_loss)]
+    let nanos = duration.as_nanos() as f64;
+    let (value, unit) = if nanos < 1_000.0 {
+        (nanos, "ns")
+    } else if nanos < 1_000_000.0 {
+        (nanos / 1_000.0, "µs")
+    } else if nanos < 1_000_000_000.0 {
+        (nanos / 1_000_000.0, "ms")
+    } else {
+        (nanos / 1_000_000_000.0, "s")
+    };
+
+    if value < 10.0 {
+        format!("{value:.1}{unit}")
+    } else {
+        format!("{value:.0}{unit}")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use chrono::{DateTime, Utc};
+
+    use super::TaskStats;
+    use crate::{fmt::WakerOp, test_support::lines_of, Clock, Layer};
+
+    fn at(micros: i64) -> DateTime<Utc> {
+        DateTime::<Utc>::UNIX_EPOCH + chrono::Duration::microseconds(micros)
+    }
+
+    #[test]
+    fn sched_latency_is_measured_from_the_first_wake() {
+        let mut stats = TaskStats::new(at(0));
+        assert_eq!(stats.poll_start(at(1)), None);
+        stats.poll_end(at(2));
+
+        stats.waker_op(WakerOp::WakeByRef, at(10));
+        stats.waker_op(WakerOp::WakeByRef, at(20));
+        assert_eq!(stats.poll_start(at(45)), Some(Duration::from_micros(35)));
+        stats.poll_end(at(50));
+
+        // The latency is only measured once per wake.
+        assert_eq!(stats.poll_start(at(60)), None);
+    }
+
+    #[test]
+    fn sched_latency_is_shown_and_warned_about() {
+        let lines = lines_of(
+            Layer::builder()
+                .with_clock(Clock::logical())
+                .with_sched_latency_threshold(Duration::ZERO),
+            async {
+                tokio::task::Builder::new()
+                    .name("yielder")
+                    .spawn(tokio::task::yield_now())
+                    .unwrap()
+                    .await
+                    .unwrap();
+            },
+        );
+        let yielder = lines
+            .iter()
+            .filter(|line| line.contains("task.name=yielder,"))
+            .collect::<Vec<_>>();
+
+        // The first poll isn't woken, the second is woken by the yield.
+        let enters = yielder
+            .iter()
+            .filter_map(|line| line.split_once("} enter").map(|(_, enter)| enter))
+            .collect::<Vec<_>>();
+        assert_eq!(enters.len(), 3);
+        assert_eq!(enters[0], "");
+        assert!(enters[1].starts_with(" (sched "), "{}", enters[1]);
+        assert!(yielder
+            .iter()
+            .any(|line| line.contains("to be polled after being woken")));
+    }
+}
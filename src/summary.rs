@@ -0,0 +1,526 @@
+//! A summary of a run, written when it ends.
+//!
+//! See the documentation on [`SummaryGuard`] for more details.
+use std::{
+    cmp::Reverse,
+    collections::{BTreeMap, HashMap},
+    io::{self, Write},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex,
+    },
+    time::Duration,
+};
+
+use chrono::{DateTime, Utc};
+use colored::Colorize;
+use tracing::span;
+use tracing_subscriber::fmt::MakeWriter;
+
+use crate::{
+    fmt::{Ansi, FieldValue, FmtSpan, SpanKind},
+    stats::{elapsed, format_duration},
+    Clock,
+};
+
+/// The number of spawn locations listed in each ranking of the summary.
+const TOP_LOCATIONS: usize = 5;
+
+/// Displayed in place of the location of a span which doesn't have one.
+const UNKNOWN_LOCATION: &str = "<unknown location>";
+
+/// Writes the summary report when it is dropped, unless it has already been written.
+///
+/// The summary is enabled with [`Builder::with_summary`] and is written when either the
+/// [`Layer`] or the guard returned by [`Layer::summary_guard`] is dropped, whichever happens
+/// first. As a layer which is set as the global default is never dropped, the guard should be
+/// held until the end of `main` in that case. If the summary isn't enabled, dropping the guard
+/// does nothing.
+///
+/// The summary covers the tasks spawned and completed, the tasks still alive, the spawn
+/// locations with the most polls and the most busy time, the resources which were never
+/// dropped, and the number of events of each kind.
+///
+/// If leak detection is enabled with [`Builder::with_leak_detection`], the spans which were
+/// never closed are listed at the same time.
+///
+/// [`Builder::with_summary`]: crate::Builder::with_summary
+/// [`Builder::with_leak_detection`]: crate::Builder::with_leak_detection
+/// [`Layer`]: crate::Layer
+/// [`Layer::summary_guard`]: crate::Layer::summary_guard
+///
+/// # Examples
+///
+/// ```rust
+/// use tracing_subscriber::prelude::*;
+///
+/// #[tokio::main]
+/// async fn main() {
+///     let layer = ari_subscriber::Layer::builder().with_summary(true).build();
+///     let _guard = layer.summary_guard();
+///     tracing_subscriber::registry().with(layer).init();
+///
+///     tokio::spawn(async {}).await.unwrap();
+///
+///     // The summary is written when `_guard` is dropped at the end of `main`.
+/// }
+/// ```
+#[must_use = "The summary is written when the guard is dropped."]
+pub struct SummaryGuard<W = fn() -> io::Stdout>
+where
+    W: for<'w> MakeWriter<'w> + 'static,
+{
+    summary: Option<Arc<Summary>>,
+    make_writer: Arc<W>,
+    ansi: bool,
+}
+
+impl<W> SummaryGuard<W>
+where
+    W: for<'w> MakeWriter<'w> + 'static,
+{
+    pub(crate) fn new(summary: Option<Arc<Summary>>, make_writer: Arc<W>, ansi: bool) -> Self {
+        Self {
+            summary,
+            make_writer,
+            ansi,
+        }
+    }
+}
+
+impl<W> Drop for SummaryGuard<W>
+where
+    W: for<'w> MakeWriter<'w> + 'static,
+{
+    fn drop(&mut self) {
+        if let Some(summary) = &self.summary {
+            summary.write(&*self.make_writer, self.ansi);
+        }
+    }
+}
+
+/// The statistics for a run which are shared between a layer, its handles, and its summary
+/// guards.
+pub(crate) struct Summary {
+    stats: Mutex<RunStats>,
+    clock: Clock,
+    written: AtomicBool,
+    /// Whether the summary report is written at the end of the run.
+    report_summary: bool,
+    /// Whether the spans which were never closed are listed at the end of the run.
+    report_leaks: bool,
+}
+
+#[derive(Default)]
+struct RunStats {
+    tasks_spawned: u64,
+    tasks_completed: u64,
+    locations: HashMap<String, LocationStats>,
+    resources_created: u64,
+    resources_dropped: u64,
+    /// The task, resource, and async op spans which haven't been closed.
+    live_spans: HashMap<span::Id, LiveSpan>,
+    events: BTreeMap<&'static str, u64>,
+}
+
+/// A task, resource, or async op span which hasn't been closed.
+struct LiveSpan {
+    kind: SpanKind,
+    name: String,
+    /// The id of the span as it is displayed.
+    display_id: u64,
+    /// What the span represents, e.g. `task`, `Semaphore`, or `Sleep::new_timeout`.
+    description: String,
+    location: Option<String>,
+    created: DateTime<Utc>,
+    /// The last lifecycle message for the span, e.g. `enter`.
+    last_message: String,
+}
+
+/// The statistics for all the tasks spawned at a single location.
+#[derive(Default)]
+struct LocationStats {
+    tasks: u64,
+    polls: u64,
+    busy: Duration,
+}
+
+impl Summary {
+    pub(crate) fn new(report_summary: bool, report_leaks: bool, clock: Clock) -> Self {
+        Self {
+            clock,
+            stats: Mutex::default(),
+            written: AtomicBool::new(false),
+            report_summary,
+            report_leaks,
+        }
+    }
+
+    /// Records a new task, resource, or async op span.
+    pub(crate) fn new_span(&self, now: DateTime<Utc>, id: &span::Id, fmt_span: &FmtSpan) {
+        let description = match fmt_span.kind() {
+            SpanKind::Spawn => match fmt_span.field("task.name").and_then(FieldValue::as_str) {
+                Some(task_name) if !task_name.is_empty() => format!("task {task_name}"),
+                _ => "task".to_owned(),
+            },
+            SpanKind::Resource => fmt_span.concrete_type().unwrap_or("resource").to_owned(),
+            SpanKind::AsyncOp => fmt_span
+                .field("source")
+                .and_then(FieldValue::as_str)
+                .unwrap_or("async op")
+                .to_owned(),
+            SpanKind::AsyncOpPoll | SpanKind::Unknown => return,
+        };
+        let live_span = LiveSpan {
+            kind: fmt_span.kind().clone(),
+            name: fmt_span.name().to_owned(),
+            display_id: fmt_span.display_id(),
+            description,
+            location: fmt_span.location(),
+            created: now,
+            last_message: "new".to_owned(),
+        };
+
+        let mut stats = self.stats();
+        match live_span.kind {
+            SpanKind::Spawn => {
+                stats.tasks_spawned += 1;
+                stats
+                    .locations
+                    .entry(live_span.location_or_unknown().to_owned())
+                    .or_default()
+                    .tasks += 1;
+            }
+            SpanKind::Resource => stats.resources_created += 1,
+            _ => {}
+        }
+        stats.live_spans.insert(id.clone(), live_span);
+    }
+
+    /// Records the last lifecycle message for a span.
+    pub(crate) fn lifecycle(&self, id: &span::Id, message: &str) {
+        if let Some(live_span) = self.stats().live_spans.get_mut(id) {
+            message.clone_into(&mut live_span.last_message);
+        }
+    }
+
+    /// Records a span being closed.
+    pub(crate) fn close_span(&self, id: &span::Id) {
+        let mut stats = self.stats();
+        match stats.live_spans.remove(id).map(|live_span| live_span.kind) {
+            Some(SpanKind::Spawn) => stats.tasks_completed += 1,
+            Some(SpanKind::Resource) => stats.resources_dropped += 1,
+            _ => {}
+        }
+    }
+
+    /// Records a poll of a task, against the task's spawn location.
+    pub(crate) fn poll(&self, id: &span::Id, poll_duration: Duration) {
+        let mut stats = self.stats();
+        let RunStats {
+            live_spans,
+            locations,
+            ..
+        } = &mut *stats;
+        if let Some(live_span) = live_spans.get(id) {
+            let location = live_span.location_or_unknown();
+            let location_stats = locations.entry(location.to_owned()).or_default();
+            location_stats.polls += 1;
+            location_stats.busy += poll_duration;
+        }
+    }
+
+    /// Removes an enter and exit of a task which was counted as a poll, but wasn't one, see
+    /// [`TaskStats::close`].
+    ///
+    /// [`TaskStats::close`]: crate::stats::TaskStats::close
+    pub(crate) fn discard_poll(&self, id: &span::Id, poll_duration: Duration) {
+        let mut stats = self.stats();
+        let RunStats {
+            live_spans,
+            locations,
+            ..
+        } = &mut *stats;
+        if let Some(live_span) = live_spans.get(id) {
+            if let Some(location_stats) = locations.get_mut(live_span.location_or_unknown()) {
+                location_stats.polls = location_stats.polls.saturating_sub(1);
+                location_stats.busy = location_stats.busy.saturating_sub(poll_duration);
+            }
+        }
+    }
+
+    /// Counts an event, by the name of its kind.
+    pub(crate) fn event(&self, kind: &'static str) {
+        *self.stats().events.entry(kind).or_default() += 1;
+    }
+
+    /// Writes the reports for the end of the run, unless they have already been written.
+    pub(crate) fn write<W>(&self, make_writer: &W, ansi: bool)
+    where
+        W: for<'w> MakeWriter<'w> + 'static,
+    {
+        if self.written.swap(true, Ordering::AcqRel) {
+            return;
+        }
+        let stats = self.stats();
+        let mut report = String::new();
+        if self.report_summary {
+            report.push_str(&stats.report(ansi));
+        }
+        if self.report_leaks {
+            report.push_str(&stats.leaks(self.clock.now(), ansi));
+        }
+        drop(stats);
+        let mut writer = make_writer.make_writer();
+        let _ = writer.write_all(report.as_bytes());
+    }
+
+    /// Writes the list of spans which haven't been closed.
+    pub(crate) fn write_leaks<W>(&self, make_writer: &W, ansi: bool)
+    where
+        W: for<'w> MakeWriter<'w> + 'static,
+    {
+        let leaks = self.stats().leaks(self.clock.now(), ansi);
+        let mut writer = make_writer.make_writer();
+        let _ = writer.write_all(leaks.as_bytes());
+    }
+
+    fn stats(&self) -> std::sync::MutexGuard<'_, RunStats> {
+        // The statistics are still usable if a thread panicked while holding the lock.
+        self.stats
+            .lock()
+            .unwrap_or_else(std::sync::PoisonError::into_inner)
+    }
+}
+
+impl RunStats {
+    fn report(&self, ansi: bool) -> String {
+        let heading = |text: &str| heading(text, ansi);
+        let mut report = format!("{}\n", heading("ari summary"));
+
+        let live_tasks = self.live_locations(&SpanKind::Spawn);
+        report.push_str(&format!(
+            "  {}: {spawned} spawned, {completed} completed, {alive} alive\n",
+            heading("tasks"),
+            spawned = self.tasks_spawned,
+            completed = self.tasks_completed,
+            alive = live_tasks.values().sum::<usize>(),
+        ));
+        for (location, count) in live_tasks {
+            report.push_str(&format!("    alive: {count} {location}\n"));
+        }
+
+        let mut locations = self.locations.iter().collect::<Vec<_>>();
+        if !locations.is_empty() {
+            report.push_str(&format!("  {}:\n", heading("spawn locations by polls")));
+            locations.sort_by_key(|(location, stats)| (Reverse(stats.polls), *location));
+            for (location, stats) in locations.iter().take(TOP_LOCATIONS) {
+                report.push_str(&format!(
+                    "    {polls:>8} polls  {tasks:>5} tasks  {location}\n",
+                    polls = stats.polls,
+                    tasks = stats.tasks,
+                ));
+            }
+
+            report.push_str(&format!("  {}:\n", heading("spawn locations by busy time")));
+            locations.sort_by_key(|(location, stats)| (Reverse(stats.busy), *location));
+            for (location, stats) in locations.iter().take(TOP_LOCATIONS) {
+                report.push_str(&format!(
+                    "    {busy:>8} busy   {tasks:>5} tasks  {location}\n",
+                    busy = format_duration(stats.busy),
+                    tasks = stats.tasks,
+                ));
+            }
+        }
+
+        let live_resources = self.live_locations(&SpanKind::Resource);
+        report.push_str(&format!(
+            "  {}: {created} created, {dropped} dropped, {live} never dropped\n",
+            heading("resources"),
+            created = self.resources_created,
+            dropped = self.resources_dropped,
+            live = live_resources.values().sum::<usize>(),
+        ));
+        for (resource, count) in live_resources {
+            report.push_str(&format!("    never dropped: {count} {resource}\n"));
+        }
+
+        if !self.events.is_empty() {
+            let events = self
+                .events
+                .iter()
+                .map(|(kind, count)| format!("{kind}={count}"))
+                .collect::<Vec<_>>()
+                .join(", ");
+            report.push_str(&format!("  {}: {events}\n", heading("events")));
+        }
+
+        report
+    }
+}
+
+impl RunStats {
+    /// Lists the task, resource, and async op spans which haven't been closed, oldest first.
+    ///
+    /// Spans created at the same time are ordered by location and then by id, so that the list
+    /// is the same from one run to the next with a deterministic clock.
+    fn leaks(&self, now: DateTime<Utc>, ansi: bool) -> String {
+        let mut live_spans = self.live_spans.values().collect::<Vec<_>>();
+        live_spans.sort_by(|a, b| {
+            a.created
+                .cmp(&b.created)
+                .then_with(|| a.location_or_unknown().cmp(b.location_or_unknown()))
+                .then_with(|| a.display_id.cmp(&b.display_id))
+        });
+
+        let mut leaks = format!(
+            "{heading}: {count} never closed\n",
+            heading = heading("ari leaks", ansi),
+            count = live_spans.len(),
+        );
+        for live_span in live_spans {
+            let created = match live_span.kind {
+                SpanKind::Spawn => "spawned",
+                _ => "created",
+            };
+            leaks.push_str(&format!(
+                "  {name}[{id}] {description} {created} at {location}, age {age}, last: {last}\n",
+                name = live_span.name,
+                id = live_span.display_id,
+                description = live_span.description,
+                location = live_span.location_or_unknown(),
+                age = format_duration(elapsed(live_span.created, now)),
+                last = live_span.last_message,
+            ));
+        }
+        leaks
+    }
+
+    /// Counts the live spans of a kind, grouped by description and location.
+    fn live_locations(&self, kind: &SpanKind) -> BTreeMap<String, usize> {
+        let mut grouped = BTreeMap::new();
+        for live_span in self.live_spans.values() {
+            if live_span.kind != *kind {
+                continue;
+            }
+            let key = match live_span.kind {
+                SpanKind::Spawn => format!("spawned at {}", live_span.location_or_unknown()),
+                _ => format!(
+                    "{description} at {location}",
+                    description = live_span.description,
+                    location = live_span.location_or_unknown()
+                ),
+            };
+            *grouped.entry(key).or_default() += 1;
+        }
+        grouped
+    }
+}
+
+impl LiveSpan {
+    fn location_or_unknown(&self) -> &str {
+        self.location.as_deref().unwrap_or(UNKNOWN_LOCATION)
+    }
+}
+
+/// Formats a heading in the reports, which is bold if ANSI escape codes are enabled.
+fn heading(text: &str, ansi: bool) -> String {
+    text.bold().ansi(ansi).to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use tracing_subscriber::prelude::*;
+
+    use crate::{
+        test_support::{lines_of, run, Output},
+        Clock, Layer,
+    };
+
+    #[test]
+    fn summary_is_written_once_when_the_layer_is_dropped() {
+        let lines = lines_of(Layer::builder().with_summary(true), async {
+            tokio::spawn(tokio::task::yield_now()).await.unwrap();
+        });
+
+        let start = lines
+            .iter()
+            .position(|line| line == "ari summary")
+            .expect("the summary should be written");
+        let summary = &lines[start..];
+        assert_eq!(summary[1], "  tasks: 2 spawned, 2 completed, 0 alive");
+        assert!(summary
+            .iter()
+            .any(|line| line.starts_with("           2 polls      1 tasks  src/summary.rs:")));
+        assert!(summary
+            .iter()
+            .any(|line| line.starts_with("  resources: ") && line.ends_with(", 0 never dropped")));
+        assert_eq!(
+            lines.iter().filter(|line| *line == "ari summary").count(),
+            1
+        );
+    }
+
+    #[test]
+    fn summary_is_written_once_by_the_layer_or_its_guard() {
+        let output = Output::default();
+        let layer = Layer::builder()
+            .with_writer(output.clone())
+            .with_summary(true)
+            .build();
+        let guard = layer.summary_guard();
+        run(tracing_subscriber::registry().with(layer), async {
+            tokio::spawn(async {}).await.unwrap();
+        });
+        // The layer was dropped along with the registry, which wrote the summary.
+        assert_eq!(output.contents().matches("ari summary").count(), 1);
+
+        drop(guard);
+        assert_eq!(output.contents().matches("ari summary").count(), 1);
+    }
+
+    #[test]
+    fn leaks_are_listed_oldest_first() {
+        let output = Output::default();
+        let layer = Layer::builder()
+            .with_writer(output.clone())
+            .with_ansi(false)
+            .with_clock(Clock::logical())
+            .with_leak_detection(true)
+            .build();
+        let handle = layer.handle();
+        run(tracing_subscriber::registry().with(layer), async {
+            tokio::spawn(async {}).await.unwrap();
+            std::mem::forget(tokio::sync::Semaphore::new(1));
+        });
+        handle.write_leaks();
+
+        // The forgotten semaphore keeps the task it was created in open too. Tokio's semaphore
+        // has an inner resource, which is created without a location.
+        let lines = output.lines();
+        let start = lines
+            .iter()
+            .position(|line| line.starts_with("ari leaks: "))
+            .expect("the leaks should be written");
+        let leaks = &lines[start..];
+        assert_eq!(leaks[0], "ari leaks: 3 never closed");
+        assert!(leaks[1].starts_with("  runtime.spawn["));
+        assert!(leaks[1].contains("] task spawned at src/test_support.rs:"));
+        assert!(leaks[2].contains("] Semaphore created at src/summary.rs:"));
+        assert!(leaks[3].contains("] Semaphore created at <unknown location>"));
+        assert!(leaks[1..].iter().all(|line| line.ends_with(", last: exit")));
+    }
+
+    #[test]
+    fn leaks_need_tracking() {
+        let output = Output::default();
+        let layer = Layer::builder().with_writer(output.clone()).build();
+        let handle = layer.handle();
+        run(tracing_subscriber::registry().with(layer), async {
+            std::mem::forget(tokio::sync::Semaphore::new(1));
+        });
+        handle.write_leaks();
+
+        assert!(!output.contents().contains("ari leaks"));
+    }
+}
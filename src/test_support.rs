@@ -0,0 +1,178 @@
+//! Support for asserting on Tokio's runtime behaviour in tests.
+//!
+//! This module is only available with the `test-support` feature enabled.
+//!
+//! Instead of printing, the layer created by [`layer`] records the lifecycle of the tasks,
+//! resources, and async ops in Tokio's instrumentation, as well as the wakes of tasks, in a
+//! [`Recording`]. The recording can then be queried to assert on the runtime's behaviour, e.g.
+//! that a sleep was polled exactly twice, or that a `Sleep` resource was dropped before the task
+//! which created it completed.
+//!
+//! Tokio's instrumentation is only emitted when compiled with `--cfg tokio_unstable` and the
+//! `tracing` feature of Tokio enabled.
+//!
+//! # Examples
+//!
+//! ```rust
+//! use std::time::Duration;
+//!
+//! use ari_subscriber::test_support;
+//!
+//! #[tokio::main(flavor = "current_thread")]
+//! async fn main() {
+//!     let (recording, _guard) = test_support::set_default();
+//!
+//!     tokio::task::Builder::new()
+//!         .name("sleeper")
+//!         .spawn(async {
+//!             tokio::time::sleep(Duration::from_millis(1)).await;
+//!         })
+//!         .unwrap()
+//!         .await
+//!         .unwrap();
+//!
+//!     let task = recording.task_named("sleeper").unwrap();
+//!     let sleep = &recording.resources_of_type("Sleep")[0];
+//!     assert_eq!(sleep.task, Some(task.span_id));
+//!     assert!(sleep.dropped.unwrap() < task.closed.unwrap());
+//!
+//!     let async_op = &recording.async_ops()[0];
+//!     assert_eq!(async_op.resource, Some(sleep.span_id));
+//!     assert_eq!(async_op.pending_polls, 1);
+//!     assert!(async_op.ready);
+//! }
+//! ```
+#[cfg(test)]
+use std::future::Future;
+use std::{
+    io,
+    sync::{Arc, Mutex},
+};
+
+use tracing_subscriber::{fmt::MakeWriter, prelude::*};
+
+pub use crate::recording::{
+    AsyncOpRecord, EventRecord, Lifecycle, Record, Recording, ResourceRecord, TaskRecord,
+    WakeRecord,
+};
+use crate::Layer;
+#[cfg(test)]
+use crate::{Builder, Timestamp};
+
+/// Creates a [`Layer`] which records into the returned [`Recording`] instead of printing.
+///
+/// The layer doesn't format any lines, so recording costs little more than the bookkeeping of
+/// the records themselves.
+pub fn layer() -> (Layer<fn() -> io::Sink>, Recording) {
+    let recording = Recording::default();
+    let layer = Layer::builder()
+        .with_writer(io::sink as fn() -> io::Sink)
+        .build()
+        .with_recording(recording.clone());
+    (layer, recording)
+}
+
+/// Sets a registry with a recording [`Layer`] as the default subscriber for the current thread,
+/// until the returned guard is dropped.
+///
+/// As the default is only set for the current thread, this is suited to tests which use Tokio's
+/// current thread runtime, such as `#[tokio::test]`. For the multi-threaded runtime, add the
+/// layer from [`layer`] to the global default subscriber instead.
+pub fn set_default() -> (Recording, tracing::subscriber::DefaultGuard) {
+    let (layer, recording) = layer();
+    let guard = tracing_subscriber::registry().with(layer).set_default();
+    (recording, guard)
+}
+
+/// A writer which keeps everything written to it, to assert on the lines a [`Layer`] writes.
+///
+/// Outputs are cheap to clone, all clones share the same buffer.
+///
+/// # Examples
+///
+/// ```rust
+/// use tracing_subscriber::prelude::*;
+///
+/// use ari_subscriber::{test_support::Output, Layer};
+///
+/// let output = Output::default();
+/// let layer = Layer::builder()
+///     .with_writer(output.clone())
+///     .with_ansi(false)
+///     .build();
+/// tracing::subscriber::with_default(tracing_subscriber::registry().with(layer), || {
+///     tracing::info!("written");
+/// });
+///
+/// assert!(output.lines()[0].ends_with("written"));
+/// ```
+#[derive(Clone, Debug, Default)]
+pub struct Output {
+    buffer: Arc<Mutex<Vec<u8>>>,
+}
+
+impl Output {
+    /// Returns the lines written so far, without their line endings.
+    #[must_use]
+    pub fn lines(&self) -> Vec<String> {
+        self.contents().lines().map(str::to_owned).collect()
+    }
+
+    /// Returns everything written so far.
+    #[must_use]
+    pub fn contents(&self) -> String {
+        let buffer = self.buffer.lock().expect("Output lock poisoned");
+        String::from_utf8_lossy(&buffer).into_owned()
+    }
+}
+
+impl io::Write for Output {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.buffer
+            .lock()
+            .expect("Output lock poisoned")
+            .extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl<'w> MakeWriter<'w> for Output {
+    type Writer = Self;
+
+    fn make_writer(&'w self) -> Self::Writer {
+        self.clone()
+    }
+}
+
+/// Runs `future` to completion on a current thread runtime, with `subscriber` as the default.
+#[cfg(test)]
+pub(crate) fn run<S>(subscriber: S, future: impl Future<Output = ()>)
+where
+    S: tracing::Subscriber + Send + Sync + 'static,
+{
+    tracing::subscriber::with_default(subscriber, || {
+        tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .unwrap()
+            .block_on(future);
+    });
+}
+
+/// Runs `future` with a layer configured by `builder`, writing plain text lines without
+/// timestamps, and returns the lines written.
+#[cfg(test)]
+pub(crate) fn lines_of(builder: Builder, future: impl Future<Output = ()>) -> Vec<String> {
+    let output = Output::default();
+    let layer = builder
+        .with_writer(output.clone())
+        .with_ansi(false)
+        .with_timestamp(Timestamp::None)
+        .build();
+    run(tracing_subscriber::registry().with(layer), future);
+    output.lines()
+}